@@ -0,0 +1,46 @@
+use regex::Regex;
+
+use std::iter;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+/// ANSI escape sequences, like colors (`ESC[36m`) and erasing the line (`ESC[K`)
+static ESCAPE_REGEX: LazyLock<Regex> =
+  LazyLock::new(|| Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]").unwrap());
+
+/// A line with its escape sequences removed, for matching prefixes and fields against, along with
+/// where each of its bytes is in the original line
+pub struct Stripped {
+  pub text: String,
+  /// Index in the original line of each byte of `text`, followed by the length of the line
+  offsets: Vec<usize>,
+}
+
+impl Stripped {
+  pub fn new(line: &str) -> Stripped {
+    let mut text = String::with_capacity(line.len());
+    let mut offsets = Vec::with_capacity(line.len() + 1);
+    let mut copied = 0;
+    for escape in ESCAPE_REGEX
+      .find_iter(line)
+      .map(|m| m.range())
+      .chain(iter::once(line.len()..line.len()))
+    {
+      text.push_str(&line[copied..escape.start]);
+      offsets.extend(copied..escape.start);
+      copied = escape.end;
+    }
+    offsets.push(line.len());
+    Stripped { text, offsets }
+  }
+
+  /// The part of the original line holding `range` of the text, with the escape sequences around
+  /// it so it keeps its colors
+  pub fn original_range(&self, range: Range<usize>) -> Range<usize> {
+    let start = match range.start {
+      0 => 0,
+      start => self.offsets[start - 1] + 1,
+    };
+    start..self.offsets[range.end]
+  }
+}
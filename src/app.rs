@@ -1,23 +1,40 @@
-use anyhow::Result;
-use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers, MouseEvent, MouseEventKind};
-use crossterm::terminal::ScrollUp;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Local;
+use crossterm::event::{
+  self, Event, KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+};
 use log::debug;
 use log::info;
+use nix::pty::Winsize;
+use nix::unistd::Pid;
 use regex::Regex;
-use std::fs::{File, OpenOptions};
-use std::io::{LineWriter, Write};
-use std::process::{Command, ExitCode, ExitStatus, Stdio};
-use std::sync::mpsc::Receiver;
+use std::collections::HashSet;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Stdout;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
 use std::time::Instant;
 use std::{collections::HashMap, time::Duration};
-use std::{collections::LinkedList, io::Stdout};
-use std::{env, fs};
-use tempfile::NamedTempFile;
-use tui::{backend::CrosstermBackend, widgets::ListState, Terminal};
+use tui::{backend::CrosstermBackend, layout::Rect, widgets::ListState, Terminal};
 
-use crate::cli::Config;
-use crate::render::DisplayState;
-use crate::{bucket::Bucket, render::draw};
+use crate::ansi::Stripped;
+use crate::bucket::{Bucket, Filter};
+use crate::cli::{Config, Format, INDENT, INDENT_REGEX};
+use crate::keys::{Action, KeyMap};
+use crate::logs::{sanitize_prefix, Log, LogFiles};
+use crate::presets::{self, Preset, AUTO, AUTO_SAMPLE_SIZE, AUTO_SAMPLE_TIME};
+use crate::process::{self, Process, ShutdownStep};
+use crate::render::draw;
+use crate::render::{DisplayState, Theme};
+use crate::severity::{Severity, SeverityRules};
+use crate::structured::{self, FieldNames};
+use crate::timeline::Timeline;
+use crate::watch::{self, WatchFilter};
 
 #[derive(Clone, Debug)]
 pub enum AppMessage {
@@ -25,11 +42,12 @@ pub enum AppMessage {
 }
 
 impl Line {
-  pub fn with_prefix(prefix: String, message: String, has_error: bool) -> Self {
+  pub fn with_prefix(prefix: String, message: String, severity: Option<Severity>) -> Self {
     Self {
       prefix: Some(prefix),
       message,
-      has_error,
+      severity,
+      ..Default::default()
     }
   }
   pub fn without_prefix(message: String) -> Self {
@@ -41,6 +59,36 @@ impl Line {
   }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PromptKind {
+  Search,
+  Filter,
+}
+
+/// Text input shown below the messages
+#[derive(Clone, Debug)]
+pub struct Prompt {
+  pub kind: PromptKind,
+  pub text: String,
+}
+
+impl Prompt {
+  pub fn label(&self) -> &'static str {
+    match self.kind {
+      PromptKind::Search => "/",
+      PromptKind::Filter => "filter: ",
+    }
+  }
+}
+
+/// A restart waiting for the commands to be shut down
+struct Restart {
+  /// The process groups still running at the end of the shutdown sequence, sent once it is done
+  survivors: Receiver<Vec<Pid>>,
+  /// Files whose changes caused the restart
+  changed: Vec<PathBuf>,
+}
+
 pub struct App {
   /// Messages by prefix
   pub buckets: HashMap<String, Bucket>,
@@ -48,148 +96,976 @@ pub struct App {
   pub unprefixed_messages: Bucket,
   pub list_state: ListState,
   pub display_state: DisplayState,
-  config: Config,
+  format: Format,
+  fields: FieldNames,
   regex: Regex,
-  error_regex: Regex,
+  /// Whether lines that don't match the prefix regex go in the bucket of the last line that did
+  sticky: bool,
+  last_prefix: Option<String>,
+  /// Regex for lines that continue the previous record, like the lines of a stack trace
+  continuation: Option<Regex>,
+  /// Bucket of the last record of the output and of the stderr of each process, `None` for the
+  /// unparsable messages
+  last_bucket: HashMap<(Option<String>, bool), Option<String>>,
+  /// Whether new multi-line records are folded
+  fold: bool,
+  /// Least severe messages shown in new buckets, and in all buckets when cycled globally
+  min_severity: Option<Severity>,
+  /// Area of the message pane and the prefix, sequence number and height of each record shown in
+  /// it, for finding the record that was clicked
+  pub message_area: Rect,
+  pub shown_records: Vec<(String, u64, usize)>,
+  /// Lines of output and stderr held back while the `auto` preset is picking a preset, with
+  /// whether they were written to stderr
+  sample: Option<Vec<(Option<String>, String, bool)>>,
+  /// When the first line of the sample arrived
+  sample_started: Option<Instant>,
+  severity_rules: SeverityRules,
+  /// Whether to split the output of named commands with the prefix regex
+  split: bool,
+  /// Maximum number of lines kept per bucket
+  max_lines: Option<usize>,
+  /// Maximum number of bytes kept across all buckets
+  max_memory: Option<usize>,
+  /// Log files that every bucket is written to, when `--out-dir` is given
+  logs: Option<LogFiles>,
+  /// Signals sent to the process groups of the commands when they are stopped
+  pub shutdown: Vec<ShutdownStep>,
+  /// Whether restarting the commands clears the buckets
+  clear_on_restart: bool,
+  /// Files that changed in the watched paths, when `--watch` is given
+  watch: Option<Receiver<Vec<PathBuf>>>,
+  /// Whether the TUI quits once all commands have exited
+  auto_exit: bool,
+  /// The restart in progress, while the commands are being shut down
+  restarting: Option<Restart>,
+  /// Whether the commands run in a pseudo-terminal
+  pty: bool,
+  /// Size of the pseudo-terminal when it is fixed rather than following the message pane
+  pty_size: Option<Winsize>,
+  pub timeline: Timeline,
+  /// Prefixes marked in the prefix list. When any are marked, only they are merged in the timeline
+  pub marked: HashSet<String>,
+  /// Sequence number given to the next line
+  next_seq: u64,
+  keys: KeyMap,
+  pub theme: Theme,
+  /// The search or filter prompt, while it is open
+  pub prompt: Option<Prompt>,
+  /// Search used to highlight and jump between matches in the current bucket
+  pub search: Option<Regex>,
+  /// Prefix and sequence number of the error line jumped to with `next-error` or `previous-error`
+  pub current_error: Option<(String, u64)>,
   pub exit_code: Option<ExitStatus>,
+  /// Exit statuses of named commands
+  pub exit_codes: HashMap<String, ExitStatus>,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Line {
   pub prefix: Option<String>,
   pub message: String,
-  pub has_error: bool,
+  pub severity: Option<Severity>,
+  /// Captured by the optional `level` group of the prefix regex
+  pub level: Option<String>,
+  /// Captured by the optional `timestamp` group of the prefix regex
+  pub timestamp: Option<String>,
+  /// Captured by the optional `source` group of the prefix regex
+  pub source: Option<String>,
+  /// Global arrival order of the line, used to merge buckets
+  pub seq: u64,
+  /// The line as received, for structured formats where the message is only part of it
+  pub raw: Option<String>,
+  /// Whether the line continues the last record of its bucket rather than starting a new one
+  pub continues: bool,
+  /// Whether only the first line of a multi-line record is shown
+  pub folded: bool,
+  /// Whether the line was written to stderr
+  pub stderr: bool,
+  /// Whether the line marks a restart of the commands rather than being output
+  pub separator: bool,
 }
 
 impl Line {
   pub fn render(&self) -> String {
-    return format!(
-      "{}{}",
+    if self.separator {
+      return format!("── {} ──", self.message);
+    }
+    format!(
+      "{}{}{}{}{}",
+      self
+        .timestamp
+        .as_ref()
+        .map(|t| format!("{} ", t))
+        .unwrap_or_default(),
       self
         .prefix
         .as_ref()
         .map(|p| format!("{}: ", p))
         .unwrap_or_default(),
+      self
+        .level
+        .as_ref()
+        .map(|l| format!("{} ", l))
+        .unwrap_or_default(),
+      self
+        .source
+        .as_ref()
+        .map(|s| format!("[{}] ", s))
+        .unwrap_or_default(),
       self.message
-    );
+    )
+  }
+
+  /// Number of rows the line takes in the message pane
+  pub fn height(&self) -> usize {
+    if self.folded {
+      1
+    } else {
+      self.message.lines().count().max(1)
+    }
+  }
+
+  /// Approximate number of bytes used by the line, for enforcing the memory budget
+  pub fn size_in_bytes(&self) -> usize {
+    std::mem::size_of::<Line>()
+      + self.message.len()
+      + [
+        &self.prefix,
+        &self.level,
+        &self.timestamp,
+        &self.source,
+        &self.raw,
+      ]
+      .iter()
+      .map(|s| s.as_ref().map_or(0, |s| s.len()))
+      .sum::<usize>()
+  }
+
+  /// Renders the line for the message pane. The timestamp and source are dimmed, the level is
+  /// coloured by severity, stderr lines get a red bar and separators are dimmed, using ANSI codes
+  /// so they go through the same path as colored output.
+  pub fn display(&self) -> String {
+    if self.separator {
+      return format!("\x1b[2m{}\x1b[0m", self.render());
+    }
+    let mut out = String::new();
+    if self.stderr {
+      out.push_str("\x1b[31m┃\x1b[0m ");
+    }
+    if let Some(timestamp) = &self.timestamp {
+      out.push_str(&format!("\x1b[2m{}\x1b[0m ", timestamp));
+    }
+    if let Some(level) = &self.level {
+      let color = self.severity.map_or(2, Severity::ansi_color);
+      out.push_str(&format!("\x1b[{}m{}\x1b[0m ", color, level));
+    }
+    if let Some(source) = &self.source {
+      out.push_str(&format!("\x1b[2m[{}]\x1b[0m ", source));
+    }
+    match self.message.split_once('\n') {
+      Some((first, rest)) if self.folded => {
+        out.push_str(first);
+        out.push_str(&format!(" \x1b[2m(+{} lines)\x1b[0m", rest.lines().count()));
+      }
+      _ => out.push_str(&self.message),
+    }
+    out
   }
 }
 
+/// Size of the inside of the message pane, for the pseudo-terminal the commands run in
+fn pane_size(area: Rect) -> Winsize {
+  Winsize {
+    ws_row: area.height.saturating_sub(2),
+    ws_col: area.width.saturating_sub(2),
+    ws_xpixel: 0,
+    ws_ypixel: 0,
+  }
+}
+
+/// Number of lines from the start of `lines` that fit in `rows` rows. The first line is always
+/// counted, even if it is taller
+pub fn lines_fitting<'a>(lines: impl IntoIterator<Item = &'a Line>, rows: usize) -> usize {
+  let mut used = 0;
+  lines
+    .into_iter()
+    .take_while(|line| {
+      let fits = used == 0 || used + line.height() <= rows;
+      used += line.height();
+      fits
+    })
+    .count()
+}
+
+/// Compiles the prefix regex, making sure it has the named groups that `parse_line` relies on
+fn compile_prefix_regex(pattern: &str) -> Result<Regex> {
+  let regex = Regex::new(pattern).with_context(|| format!("Invalid prefix regex `{}`", pattern))?;
+  for group in ["prefix", "message"] {
+    if !regex.capture_names().flatten().any(|name| name == group) {
+      bail!(
+        "Prefix regex `{}` has no `(?P<{}>...)` capture group",
+        pattern,
+        group
+      );
+    }
+  }
+  Ok(regex)
+}
+
+/// The buckets merged in the timeline
+fn timeline_buckets<'a>(
+  buckets: &'a HashMap<String, Bucket>,
+  marked: &HashSet<String>,
+) -> Vec<&'a Bucket> {
+  buckets
+    .iter()
+    .filter(|(prefix, _)| marked.is_empty() || marked.contains(*prefix))
+    .map(|(_, bucket)| bucket)
+    .collect()
+}
+
 impl App {
-  pub fn new(config: &Config) -> App {
-    App {
+  pub fn new(config: &Config) -> Result<App> {
+    let mut app = App {
       display_state: DisplayState::Messages,
       buckets: HashMap::new(),
-      error_messages: Bucket::new(),
-      unprefixed_messages: Bucket::new(),
+      error_messages: Bucket::new(config.max_lines()),
+      unprefixed_messages: Bucket::new(config.max_lines()),
       list_state: {
         let mut list_state = ListState::default();
         list_state.select(Some(0));
         list_state
       },
-      config: config.clone(),
-      regex: Regex::new(config.prefix.as_str()).unwrap(),
-      error_regex: Regex::new(r"(?i).*(error|exception|stack.?trace).*").unwrap(),
+      format: config.format,
+      fields: config.field_names(),
+      regex: compile_prefix_regex(&config.prefix)?,
+      sticky: false,
+      last_prefix: None,
+      continuation: match config.continuation.as_deref() {
+        Some(INDENT) => Some(Regex::new(INDENT_REGEX).unwrap()),
+        Some(pattern) => Some(
+          Regex::new(pattern)
+            .with_context(|| format!("Invalid continuation regex `{}`", pattern))?,
+        ),
+        None => None,
+      },
+      last_bucket: HashMap::new(),
+      fold: config.fold,
+      min_severity: None,
+      message_area: Rect::default(),
+      shown_records: vec![],
+      sample: None,
+      sample_started: None,
+      severity_rules: SeverityRules::new(&config.severity_rules)?,
+      split: config.split,
+      max_lines: config.max_lines(),
+      max_memory: config.max_memory.map(|mb| mb * 1024 * 1024),
+      logs: match &config.out_dir {
+        Some(dir) => Some(LogFiles::new(
+          dir,
+          Some(config.rotate_size * 1024 * 1024).filter(|&size| size > 0),
+          config.rotate_keep,
+        )?),
+        None => None,
+      },
+      shutdown: process::parse_shutdown_sequence(&config.shutdown).context("Invalid --shutdown")?,
+      clear_on_restart: config.clear_on_restart,
+      watch: None,
+      auto_exit: config.auto_exit,
+      restarting: None,
+      pty: config.pty,
+      pty_size: match &config.pty_size {
+        Some(size) => Some(process::parse_pty_size(size).context("Invalid --pty-size")?),
+        None => None,
+      },
+      timeline: Timeline::default(),
+      marked: HashSet::new(),
+      next_seq: 0,
+      keys: KeyMap::new(&config.keys)?,
+      theme: Theme::new(&config.colors)?,
+      prompt: None,
+      search: None,
+      current_error: None,
       exit_code: None,
+      exit_codes: HashMap::new(),
+    };
+    if !config.watch.is_empty() {
+      let ignored = config.out_dir.iter().cloned().collect::<Vec<_>>();
+      let filter = WatchFilter::new(&config.watch_include, &config.watch_exclude, &ignored)?;
+      app.watch = Some(watch::spawn_watch_thread(
+        &config.watch,
+        filter,
+        Duration::from_millis(config.watch_debounce),
+      )?);
     }
+    match config.preset.as_deref() {
+      Some(AUTO) => app.sample = Some(vec![]),
+      Some(name) => {
+        let preset = presets::find(name).ok_or_else(|| {
+          anyhow!(
+            "Unknown preset `{}`, expected one of {}",
+            name,
+            presets::names().join(", ")
+          )
+        })?;
+        app.use_preset(preset);
+      }
+      None => {}
+    }
+    Ok(app)
+  }
+
+  fn use_preset(&mut self, preset: &Preset) {
+    info!("Using preset {}", preset.name);
+    self.regex = compile_prefix_regex(preset.regex).unwrap();
+    self.sticky = preset.sticky;
+  }
+
+  /// Parses a line of output or stderr, or holds it back while the `auto` preset is sampling.
+  /// Returns the lines that are ready
+  fn receive_line(&mut self, name: Option<&String>, line: String, stderr: bool) -> Vec<Line> {
+    let Some(sample) = self.sample.as_mut() else {
+      return self
+        .parse_process_line(name, &line, stderr)
+        .into_iter()
+        .collect();
+    };
+    sample.push((name.cloned(), line, stderr));
+    self.sample_started.get_or_insert_with(Instant::now);
+    if self.sample_due() {
+      self.finish_sample()
+    } else {
+      vec![]
+    }
+  }
+
+  /// Whether the `auto` preset has seen enough lines, or waited long enough for them
+  fn sample_due(&self) -> bool {
+    self.sample.as_ref().is_some_and(|sample| {
+      sample.len() >= AUTO_SAMPLE_SIZE
+        || self
+          .sample_started
+          .is_some_and(|started| started.elapsed() >= AUTO_SAMPLE_TIME)
+    })
+  }
+
+  /// Picks the preset that best matches the sampled lines and parses them with it. The prefix
+  /// regex is kept when no preset matches
+  fn finish_sample(&mut self) -> Vec<Line> {
+    let Some(sample) = self.sample.take() else {
+      return vec![];
+    };
+    let lines = sample
+      .iter()
+      .map(|(_, l, _)| Stripped::new(l).text)
+      .collect::<Vec<_>>();
+    if let Some(preset) = presets::detect(&lines) {
+      self.use_preset(preset);
+    }
+    sample
+      .into_iter()
+      .filter_map(|(name, l, stderr)| self.parse_process_line(name.as_ref(), &l, stderr))
+      .collect()
   }
 
+  /// Runs the TUI until the user quits or prism is interrupted
   pub fn run(
     &mut self,
     terminal: &mut Terminal<CrosstermBackend<Stdout>>,
-    output: Receiver<String>,
-    errors: Receiver<String>,
-    monitor: Receiver<AppMessage>,
+    processes: &mut [Process],
+    interrupted: &AtomicBool,
   ) -> Result<(), std::io::Error> {
-    loop {
-      let height = terminal.size()?.height;
+    while !interrupted.load(Ordering::SeqCst) {
+      // Checked before taking the output, so the output written just before the commands exited
+      // is taken and shown first
+      let exited = self.auto_exit
+        && self.watch.is_none()
+        && self.restarting.is_none()
+        && self.all_exited(processes);
+      let mut received = 0;
+      let rows = self.pane_rows();
       let now = Instant::now();
       let stdout_end = now + Duration::from_millis(4);
       let stderr_end = now + Duration::from_millis(8);
       let render_end = now + Duration::from_millis(16);
-      output
-        .try_iter()
-        .take_while(|_| Instant::now() < stdout_end)
-        .for_each(|l| {
-          self
-            .parse_line(&l)
-            .into_iter()
-            .for_each(|parsed| self.process_line(&parsed));
-        });
-      errors
-        .try_iter()
-        .take_while(|_| Instant::now() < stderr_end)
-        .for_each(|l| self.process_error(&l));
+      for process in processes.iter() {
+        process
+          .output
+          .try_iter()
+          .take_while(|_| Instant::now() < stdout_end)
+          .for_each(|l| {
+            received += 1;
+            self
+              .receive_line(process.name.as_ref(), l, false)
+              .iter()
+              .for_each(|parsed| self.add_received(parsed));
+          });
+      }
+      if self.sample_due() {
+        self
+          .finish_sample()
+          .iter()
+          .for_each(|parsed| self.add_received(parsed));
+      }
+      for process in processes.iter() {
+        process
+          .errors
+          .try_iter()
+          .take_while(|_| Instant::now() < stderr_end)
+          .for_each(|l| {
+            received += 1;
+            self
+              .receive_line(process.name.as_ref(), l, true)
+              .iter()
+              .for_each(|parsed| self.add_received(parsed));
+          });
+      }
+      self.enforce_memory_budget();
 
       let remaining = render_end - Instant::now();
+      let pane = self.message_area;
       terminal.draw(|f| draw(self, f))?;
-      if let Ok(x) = monitor.try_recv() {
-        info!("Process exited: {:?}", x);
-        match x {
-          AppMessage::Exit(code) => {
-            self.notify_exit(code);
+      if self.pty && self.pty_size.is_none() && self.message_area != pane {
+        let size = pane_size(self.message_area);
+        processes.iter().for_each(|process| process.resize(size));
+      }
+      if exited && received == 0 {
+        return Ok(());
+      }
+      for process in processes.iter() {
+        if let Ok(x) = process.monitor.try_recv() {
+          info!("Process {:?} exited: {:?}", process.name, x);
+          match x {
+            AppMessage::Exit(code) => {
+              self.notify_exit(process.name.as_ref(), code);
+            }
           }
         }
       }
+      let changed = self.watched_changes();
+      if !changed.is_empty() {
+        self.restart(processes, changed);
+      }
+      self.finish_restart(processes);
       if event::poll(remaining)? {
         let event = event::read()?;
         match event {
-          Event::Key(key) => match key.code {
-            KeyCode::Char('q') => return Ok(()),
-            KeyCode::Char('c') if key.modifiers & KeyModifiers::CONTROL > KeyModifiers::NONE => {
-              return Ok(())
+          Event::Key(key) if self.prompt.is_some() => self.handle_prompt_key(key, rows),
+          Event::Key(key)
+            if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) =>
+          {
+            return Ok(())
+          }
+          Event::Key(key) => match self.keys.get(key.code) {
+            Some(Action::Quit) => return Ok(()),
+            Some(Action::NextPrefix) => self.next_prefix(),
+            Some(Action::PreviousPrefix) => self.previous_prefix(),
+            Some(Action::ScrollUp) => self.scroll_up(rows),
+            Some(Action::ScrollDown) => self.scroll_down(rows),
+            Some(Action::ScrollReset) => self.scroll_reset(),
+            Some(Action::Back) => {
+              self.clear_search();
+              self.current_error = None;
+              self.set_display_state(DisplayState::Messages)
             }
-            KeyCode::Char('j') => self.next_prefix(),
-            KeyCode::Char('k') => self.previous_prefix(),
-            KeyCode::Char('w') => self.scroll_up(height.into()),
-            KeyCode::Char('s') => self.scroll_down(height.into()),
-            KeyCode::Char('K') => self.scroll_up(height.into()),
-            KeyCode::Char('J') => self.scroll_down(height.into()),
-            KeyCode::Char('r') => self.scroll_reset(),
-            KeyCode::Esc => self.set_display_state(DisplayState::Messages),
-            KeyCode::Char('e') => self.set_display_state(DisplayState::Errors),
-            KeyCode::Char('p') => self.set_display_state(DisplayState::ParseErrors),
-            KeyCode::Char('n') => self.next_bucket(),
-            KeyCode::Char('c') => self.clear_current_bucket(),
-            KeyCode::Char('C') => self.clear_all_buckets(),
-            KeyCode::Enter => self.open_in_editor().unwrap_or(()),
-            _ => {}
+            Some(Action::ShowErrors) => self.set_display_state(DisplayState::Errors),
+            Some(Action::ShowParseErrors) => self.set_display_state(DisplayState::ParseErrors),
+            Some(Action::ShowTimeline) => self.set_display_state(DisplayState::Timeline),
+            Some(Action::ShowDetail) => self.set_display_state(DisplayState::Detail),
+            Some(Action::ToggleMarked) => self.toggle_marked(),
+            Some(Action::CycleLevel) => self.cycle_level(),
+            Some(Action::CycleGlobalLevel) => self.cycle_global_level(),
+            Some(Action::FoldAll) => self.fold_all(),
+            Some(Action::ToggleFold) => {
+              if let Some(line) = self.get_focused_line() {
+                self.toggle_fold(line.prefix.as_ref(), line.seq);
+              }
+            }
+            Some(Action::Search) => self.open_prompt(PromptKind::Search),
+            Some(Action::Filter) => self.open_prompt(PromptKind::Filter),
+            Some(Action::NextMatch) if self.search.is_some() => self.jump_to_match(true, rows),
+            Some(Action::NextMatch) => self.next_bucket(),
+            Some(Action::PreviousMatch) => self.jump_to_match(false, rows),
+            Some(Action::NextError) => self.jump_to_error(true, rows),
+            Some(Action::PreviousError) => self.jump_to_error(false, rows),
+            Some(Action::ClearBucket) => self.clear_current_bucket(),
+            Some(Action::ClearAllBuckets) => self.clear_all_buckets(),
+            Some(Action::OpenInEditor) => self.open_in_editor().unwrap_or(()),
+            Some(Action::Restart) => {
+              self.restart(processes, vec![]);
+            }
+            None => {}
           },
           Event::Mouse(mouse) => match mouse {
             MouseEvent {
               kind: MouseEventKind::ScrollUp,
               ..
-            } => self.scroll_up(height.into()),
+            } => self.scroll_up(rows),
             MouseEvent {
               kind: MouseEventKind::ScrollDown,
               ..
-            } => self.scroll_down(height.into()),
+            } => self.scroll_down(rows),
+            MouseEvent {
+              kind: MouseEventKind::Down(MouseButton::Left),
+              column,
+              row,
+              ..
+            } => self.click(column, row),
             _ => {}
           },
           _ => (),
         }
       }
     }
+    Ok(())
+  }
+
+  fn open_prompt(&mut self, kind: PromptKind) {
+    let text = match kind {
+      PromptKind::Search => String::new(),
+      PromptKind::Filter => self
+        .get_current_bucket()
+        .and_then(|b| b.get_filter().map(|f| f.text.clone()))
+        .unwrap_or_default(),
+    };
+    self.prompt = Some(Prompt { kind, text });
   }
 
-  fn notify_exit(&mut self, exit_code: ExitStatus) {
-    self.exit_code = Some(exit_code);
+  fn handle_prompt_key(&mut self, key: KeyEvent, rows: usize) {
+    let Some(prompt) = self.prompt.as_mut() else {
+      return;
+    };
+    let kind = prompt.kind;
+    match key.code {
+      KeyCode::Esc => {
+        self.prompt = None;
+        match kind {
+          PromptKind::Search => self.clear_search(),
+          PromptKind::Filter => self.set_filter(""),
+        }
+        return;
+      }
+      KeyCode::Enter => {
+        self.prompt = None;
+        return;
+      }
+      KeyCode::Backspace => {
+        prompt.text.pop();
+      }
+      KeyCode::Char(c) => prompt.text.push(c),
+      _ => return,
+    }
+    let text = prompt.text.clone();
+    match kind {
+      PromptKind::Search => self.update_search(&text, rows),
+      PromptKind::Filter => self.set_filter(&text),
+    }
   }
 
-  fn scroll_up(&mut self, height: usize) {
+  /// Filters the current bucket as the filter is typed
+  fn set_filter(&mut self, text: &str) {
     if let Some(bucket) = self.get_current_bucket() {
-      bucket.scroll_up(height);
+      bucket.set_filter(Filter::parse(text));
     }
   }
 
-  fn scroll_down(&mut self, height: usize) {
+  /// Searches for the text in the prompt and jumps to the newest match. The search is case
+  /// insensitive unless the text contains an uppercase letter.
+  fn update_search(&mut self, query: &str, rows: usize) {
+    self.search = if query.is_empty() {
+      None
+    } else {
+      let case = if query.chars().any(char::is_uppercase) {
+        ""
+      } else {
+        "(?i)"
+      };
+      Regex::new(&format!("{}{}", case, regex::escape(query))).ok()
+    };
     if let Some(bucket) = self.get_current_bucket() {
-      bucket.scroll_down(height);
+      bucket.current_match = None;
     }
+    self.jump_to_match(false, rows);
   }
 
-  fn scroll_reset(&mut self) {
+  fn clear_search(&mut self) {
+    self.search = None;
+    for bucket in self.buckets.values_mut() {
+      bucket.current_match = None;
+    }
+  }
+
+  /// Moves to the next (newer) or previous (older) match in the current bucket, wrapping around
+  fn jump_to_match(&mut self, forward: bool, rows: usize) {
+    let Some(search) = self.search.clone() else {
+      return;
+    };
+    let Some(bucket) = self.get_current_bucket() else {
+      return;
+    };
+    let matches = bucket.find_matches(&search);
+    let next = match (bucket.current_match, forward) {
+      (Some(current), true) => matches.iter().find(|&&i| i > current).or(matches.first()),
+      (Some(current), false) => matches
+        .iter()
+        .rev()
+        .find(|&&i| i < current)
+        .or(matches.last()),
+      (None, _) => matches.last(),
+    };
+    if let Some(&index) = next {
+      bucket.current_match = Some(index);
+      bucket.scroll_to(index, rows);
+    }
+  }
+
+  /// Moves to the next or previous error line, going through the buckets in the order of the prefix
+  /// list and wrapping around, and scrolls to it
+  fn jump_to_error(&mut self, forward: bool, rows: usize) {
+    let selected = self.list_state.selected().unwrap_or(0);
+    let errors = self
+      .get_buckets()
+      .into_iter()
+      .enumerate()
+      .flat_map(|(i, (prefix, bucket))| {
+        bucket
+          .find_severity(Severity::Error)
+          .into_iter()
+          .map(move |(index, seq)| (i, prefix.clone(), index, seq))
+      })
+      .collect::<Vec<_>>();
+    let current = self.current_error.as_ref().and_then(|(prefix, seq)| {
+      errors
+        .iter()
+        .position(|(_, p, _, s)| p == prefix && s == seq)
+    });
+    let next = match (current, forward) {
+      (Some(current), true) => errors.get((current + 1) % errors.len()),
+      (Some(current), false) => errors.get((current + errors.len() - 1) % errors.len()),
+      (None, true) => errors
+        .iter()
+        .find(|(i, ..)| *i >= selected)
+        .or(errors.first()),
+      (None, false) => errors
+        .iter()
+        .rev()
+        .find(|(i, ..)| *i <= selected)
+        .or(errors.last()),
+    };
+    let Some((i, prefix, index, seq)) = next.cloned() else {
+      return;
+    };
+    self.list_state.select(Some(i));
+    self.display_state = DisplayState::Messages;
+    self.current_error = Some((prefix, seq));
     if let Some(bucket) = self.get_current_bucket() {
+      bucket.scroll_to(index, rows);
+    }
+  }
+
+  /// Returns the position of the current match and the number of matches in the current bucket
+  pub fn get_match_position(&mut self) -> Option<(Option<usize>, usize)> {
+    let search = self.search.clone()?;
+    let bucket = self.get_current_bucket()?;
+    let matches = bucket.find_matches(&search);
+    let position = bucket
+      .current_match
+      .and_then(|current| matches.iter().position(|&i| i == current))
+      .map(|i| i + 1);
+    Some((position, matches.len()))
+  }
+
+  /// Runs without the TUI, passing the output through and writing each bucket to its own file,
+  /// until the processes exit or prism is interrupted. When watching files, it keeps running to
+  /// restart the processes when they change
+  pub fn run_headless(
+    &mut self,
+    processes: &mut [Process],
+    interrupted: &AtomicBool,
+  ) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    let mut open = processes.len();
+    let mut closed = vec![[false; 3]; processes.len()];
+    while (open > 0 || self.watch.is_some()) && !interrupted.load(Ordering::SeqCst) {
+      let mut idle = true;
+      for (process, closed) in processes.iter().zip(closed.iter_mut()) {
+        loop {
+          match process.output.try_recv() {
+            Ok(l) => {
+              idle = false;
+              writeln!(stdout, "{}", l)?;
+              for line in self.receive_line(process.name.as_ref(), l, false) {
+                self.write_received_log(&line);
+              }
+            }
+            Err(e) => {
+              closed[0] = e == TryRecvError::Disconnected;
+              break;
+            }
+          }
+        }
+        loop {
+          match process.errors.try_recv() {
+            Ok(l) => {
+              idle = false;
+              eprintln!("{}", l);
+              for line in self.receive_line(process.name.as_ref(), l, true) {
+                self.write_received_log(&line);
+              }
+            }
+            Err(e) => {
+              closed[1] = e == TryRecvError::Disconnected;
+              break;
+            }
+          }
+        }
+        match process.monitor.try_recv() {
+          Ok(AppMessage::Exit(code)) => {
+            info!("Process {:?} exited: {:?}", process.name, code);
+            self.notify_exit(process.name.as_ref(), code);
+          }
+          Err(e) => closed[2] = e == TryRecvError::Disconnected,
+        }
+      }
+      open = closed.iter().filter(|c| !c.iter().all(|&c| c)).count();
+      if self.sample_due() || open == 0 {
+        for line in self.finish_sample() {
+          self.write_received_log(&line);
+        }
+      }
+      let changed = self.watched_changes();
+      if !changed.is_empty() {
+        self.restart(processes, changed);
+      }
+      if let Some(marker) = self.finish_restart(processes) {
+        eprintln!("prism: {}", marker);
+        closed.fill([false; 3]);
+        open = processes.len();
+      } else if idle {
+        thread::sleep(Duration::from_millis(16));
+      }
+    }
+    Ok(())
+  }
+
+  /// Files that changed in the watched paths since the last call
+  fn watched_changes(&self) -> Vec<PathBuf> {
+    let Some(watch) = &self.watch else {
+      return vec![];
+    };
+    let mut changed = watch.try_iter().flatten().collect::<Vec<_>>();
+    changed.sort();
+    changed.dedup();
+    changed
+  }
+
+  /// Stops the commands and runs them again, because of the `changed` files if there are any. The
+  /// commands are shut down in the background, so the TUI keeps running until they are stopped and
+  /// `finish_restart` runs them again
+  fn restart(&mut self, processes: &[Process], changed: Vec<PathBuf>) {
+    if let Some(restart) = self.restarting.as_mut() {
+      restart.changed.extend(changed);
+      return;
+    }
+    let groups = processes
+      .iter()
+      .filter_map(|process| process.group)
+      .collect::<Vec<_>>();
+    let sequence = self.shutdown.clone();
+    let (tx, survivors) = mpsc::channel();
+    thread::spawn(move || tx.send(process::shutdown(&groups, &sequence)));
+    self.restarting = Some(Restart { survivors, changed });
+  }
+
+  /// Runs the commands again once they have been shut down. Output they wrote before stopping is
+  /// kept above a `restarted at HH:MM:SS` separator in every bucket, unless the buckets are
+  /// cleared. Returns the text of the separator, or `None` if the commands aren't stopped yet
+  fn finish_restart(&mut self, processes: &mut [Process]) -> Option<String> {
+    let survivors = match self.restarting.as_ref()?.survivors.try_recv() {
+      Ok(survivors) => survivors,
+      Err(TryRecvError::Empty) => return None,
+      Err(TryRecvError::Disconnected) => vec![],
+    };
+    let mut changed = self.restarting.take()?.changed;
+    changed.sort();
+    changed.dedup();
+    for group in survivors {
+      let message = format!(
+        "prism: processes still running in process group {}: {}",
+        group,
+        process::group_members(group).join(", ")
+      );
+      let line = Line {
+        seq: self.take_seq(),
+        stderr: true,
+        ..Line::without_prefix(message)
+      };
+      self.write_error_log(&line);
+      self.error_messages.add_message(line);
+    }
+    for process in processes.iter() {
+      let output = process.output.try_iter().map(|l| (l, false));
+      let errors = process.errors.try_iter().map(|l| (l, true));
+      for (l, stderr) in output.chain(errors) {
+        for parsed in self.receive_line(process.name.as_ref(), l, stderr) {
+          self.add_received(&parsed);
+        }
+      }
+    }
+    for parsed in self.finish_sample() {
+      self.add_received(&parsed);
+    }
+
+    if self.clear_on_restart {
+      self.clear_all_buckets();
+      self.error_messages.clear_all_messages();
+      self.unprefixed_messages.clear_all_messages();
+    }
+    let time = Local::now().format("%H:%M:%S");
+    let marker = match changed.as_slice() {
+      [] => format!("restarted at {}", time),
+      [file] => format!("restarted at {} after {} changed", time, file.display()),
+      files => format!("restarted at {} after {} files changed", time, files.len()),
+    };
+    self.mark_restart(&marker);
+    // Records of the stopped commands can't be continued by the new ones
+    self.last_bucket.clear();
+    self.last_prefix = None;
+    self.exit_code = None;
+    self.exit_codes.clear();
+    for process in processes.iter_mut() {
+      let Some(command) = process.command.clone() else {
+        continue;
+      };
+      info!("Restarting {:?}: {}", process.name, command);
+      match Process::spawn(process.name.clone(), command, self.spawn_pty_size()) {
+        Ok(restarted) => *process = restarted,
+        Err(e) => {
+          let message = format!("prism: failed to restart: {:#}", e);
+          if let Some(line) = self.parse_process_line(process.name.as_ref(), &message, true) {
+            self.process_error(&line);
+          }
+        }
+      }
+    }
+    Some(marker)
+  }
+
+  /// Adds a restart separator to every bucket and log file
+  fn mark_restart(&mut self, message: &str) {
+    let separator = Line {
+      separator: true,
+      ..Line::without_prefix(message.to_string())
+    };
+    if let Some(logs) = self.logs.as_mut() {
+      if let Err(e) = logs.write_all(&separator.render()) {
+        debug!("Error writing restart to log files: {}", e);
+      }
+    }
+    let prefixes = self.buckets.keys().cloned().map(Some).collect::<Vec<_>>();
+    for prefix in prefixes.into_iter().chain([None]) {
+      let line = Line {
+        prefix,
+        seq: self.take_seq(),
+        ..separator.clone()
+      };
+      self.add_to_bucket(line);
+    }
+    let line = Line {
+      seq: self.take_seq(),
+      ..separator
+    };
+    self.error_messages.add_message(line);
+  }
+
+  /// Size of the pseudo-terminal to run a command in, or `None` to run it with pipes. Before the
+  /// message pane is drawn, and without the TUI, it is the size of the terminal prism runs in
+  pub fn spawn_pty_size(&self) -> Option<Winsize> {
+    if !self.pty {
+      return None;
+    }
+    if let Some(size) = self.pty_size {
+      return Some(size);
+    }
+    if self.message_area.area() > 0 {
+      return Some(pane_size(self.message_area));
+    }
+    let (columns, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+    Some(Winsize {
+      ws_row: rows,
+      ws_col: columns,
+      ws_xpixel: 0,
+      ws_ypixel: 0,
+    })
+  }
+
+  /// Whether the commands are being shut down to be restarted
+  pub fn is_restarting(&self) -> bool {
+    self.restarting.is_some()
+  }
+
+  /// The exit status of a command, if it has exited
+  pub fn exit_status(&self, name: Option<&String>) -> Option<ExitStatus> {
+    match name {
+      Some(name) => self.exit_codes.get(name).copied(),
+      None => self.exit_code,
+    }
+  }
+
+  /// Whether all commands have exited. Input piped into prism never counts as exited
+  fn all_exited(&self, processes: &[Process]) -> bool {
+    processes
+      .iter()
+      .all(|process| process.command.is_some() && self.exit_status(process.name.as_ref()).is_some())
+  }
+
+  fn notify_exit(&mut self, name: Option<&String>, exit_code: ExitStatus) {
+    match name {
+      Some(name) => {
+        self.exit_codes.insert(name.clone(), exit_code);
+      }
+      None => self.exit_code = Some(exit_code),
+    }
+  }
+
+  /// Returns the exit status of the named command that produced the given bucket, if it has exited
+  pub fn get_exit_code_for(&self, prefix: &str) -> Option<(&String, &ExitStatus)> {
+    self.exit_codes.iter().find(|(name, _)| {
+      prefix == name.as_str()
+        || prefix
+          .strip_prefix(name.as_str())
+          .is_some_and(|rest| rest.starts_with('/'))
+    })
+  }
+
+  /// Number of rows inside the borders of the message pane
+  fn pane_rows(&self) -> usize {
+    usize::from(self.message_area.height).saturating_sub(2)
+  }
+
+  fn scroll_up(&mut self, rows: usize) {
+    if self.display_state == DisplayState::Timeline {
+      let buckets = timeline_buckets(&self.buckets, &self.marked);
+      self.timeline.scroll_up(&buckets, rows);
+    } else if let Some(bucket) = self.get_current_bucket() {
+      bucket.scroll_up(rows);
+    }
+  }
+
+  fn scroll_down(&mut self, rows: usize) {
+    if self.display_state == DisplayState::Timeline {
+      let buckets = timeline_buckets(&self.buckets, &self.marked);
+      self.timeline.scroll_down(&buckets, rows);
+    } else if let Some(bucket) = self.get_current_bucket() {
+      bucket.scroll_down(rows);
+    }
+  }
+
+  fn scroll_reset(&mut self) {
+    if self.display_state == DisplayState::Timeline {
+      self.timeline.scroll_reset();
+    } else if let Some(bucket) = self.get_current_bucket() {
       bucket.scroll_reset();
     }
   }
@@ -202,8 +1078,65 @@ impl App {
     }
   }
 
+  /// Cycles the least severe messages shown in the current bucket
+  fn cycle_level(&mut self) {
+    if let Some(bucket) = self.get_current_bucket() {
+      bucket.set_min_severity(Severity::next_minimum(bucket.get_min_severity()));
+    }
+  }
+
+  /// Cycles the least severe messages shown in all buckets
+  fn cycle_global_level(&mut self) {
+    self.min_severity = Severity::next_minimum(self.min_severity);
+    for bucket in self.buckets.values_mut() {
+      bucket.set_min_severity(self.min_severity);
+    }
+  }
+
+  /// Folds all multi-line records, or unfolds them all if new records are already folded
+  fn fold_all(&mut self) {
+    self.fold = !self.fold;
+    for bucket in self.buckets.values_mut() {
+      bucket.fold_all(self.fold);
+    }
+  }
+
+  fn toggle_fold(&mut self, prefix: Option<&String>, seq: u64) {
+    if let Some(bucket) = prefix.and_then(|prefix| self.buckets.get_mut(prefix)) {
+      bucket.toggle_fold(seq);
+    }
+  }
+
+  /// Folds or unfolds the record at the clicked row of the message pane
+  fn click(&mut self, column: u16, row: u16) {
+    let area = self.message_area;
+    // The first row and column are the border of the pane
+    if column <= area.x || column >= area.right() || row <= area.y || row >= area.bottom() {
+      return;
+    }
+    let mut top = (area.y + 1) as usize;
+    let clicked = self.shown_records.iter().find(|(_, _, height)| {
+      top += height;
+      (row as usize) < top
+    });
+    if let Some((prefix, seq, _)) = clicked.cloned() {
+      self.toggle_fold(Some(&prefix), seq);
+    }
+  }
+
+  /// Marks or unmarks the selected prefix and shows the marked buckets merged together
+  fn toggle_marked(&mut self) {
+    let Some(prefix) = self.get_selected_prefix() else {
+      return;
+    };
+    if !self.marked.remove(&prefix) {
+      self.marked.insert(prefix);
+    }
+    self.display_state = DisplayState::Timeline;
+  }
+
   fn next_prefix(&mut self) {
-    if self.buckets.len() == 0 {
+    if self.buckets.is_empty() {
       return;
     }
     self.list_state.select(
@@ -214,7 +1147,7 @@ impl App {
     );
   }
   fn previous_prefix(&mut self) {
-    if self.buckets.len() == 0 {
+    if self.buckets.is_empty() {
       return;
     }
     self.list_state.select(
@@ -226,52 +1159,227 @@ impl App {
   }
 
   fn process_line(&mut self, line: &Line) {
-    if let Some(prefix) = &line.prefix {
-      if let Some(bucket) = self.buckets.get_mut(prefix) {
-        bucket.add_message(line.clone())
-      } else {
-        self.buckets.insert(
-          prefix.to_string(),
-          Bucket::from_messages(vec![line.clone()]),
-        );
-      }
+    let line = Line {
+      seq: self.take_seq(),
+      folded: self.fold,
+      ..line.clone()
+    };
+    self.write_log(Log::for_prefix(line.prefix.as_deref()), &line);
+    self.add_to_bucket(line);
+  }
+
+  /// Adds a line to the bucket for its prefix, or to the unparsable messages
+  fn add_to_bucket(&mut self, line: Line) {
+    let bucket = match line.prefix.clone() {
+      Some(prefix) => match self.buckets.get_mut(&prefix) {
+        Some(bucket) => bucket,
+        None => {
+          let mut bucket = Bucket::from_messages(vec![line], self.max_lines);
+          bucket.set_min_severity(self.min_severity);
+          self.buckets.insert(prefix, bucket);
+          return;
+        }
+      },
+      None => &mut self.unprefixed_messages,
+    };
+    if line.continues {
+      bucket.continue_last(line);
     } else {
-      self.unprefixed_messages.add_message(line.clone());
+      bucket.add_message(line);
     }
   }
 
-  fn parse_line(&self, line: &String) -> Option<Line> {
+  fn take_seq(&mut self) -> u64 {
+    self.next_seq += 1;
+    self.next_seq
+  }
+
+  fn parse_line(&mut self, line: &String) -> Option<Line> {
     debug!("Parsing line: {}", line);
+    // Escape sequences, like the colors of commands run with `--pty`, are left out of prefixes and
+    // fields, and only kept in messages
+    let stripped = Stripped::new(line);
+    let structured = match self.format {
+      Format::Regex => None,
+      Format::Json => Some(structured::parse_json(stripped.text.trim(), &self.fields)),
+      Format::Logfmt => Some(structured::parse_logfmt(stripped.text.trim(), &self.fields)),
+    };
+    let mut res = if let Some(parsed) = structured {
+      parsed.unwrap_or_else(|| Line::without_prefix(line.trim().to_string()))
+    } else {
+      self.parse_regex_line(line, &stripped)
+    };
+    res.severity = res.severity.or_else(|| {
+      self
+        .severity_rules
+        .severity(res.level.as_deref(), &stripped.text)
+    });
+    Some(res)
+  }
+
+  /// Parses a line with the prefix regex, matched against the line without escape sequences
+  fn parse_regex_line(&mut self, line: &String, stripped: &Stripped) -> Line {
     let input = line.trim();
-    let res: Option<Line>;
-    if let Some(caps) = self.regex.captures(line) {
-      if caps.len() >= 2 {
-        let has_error = self.error_regex.is_match(line);
-        res = Some(Line::with_prefix(
-          caps[1].to_string(),
-          caps[2].to_string(),
-          has_error,
-        ));
+    let res: Line;
+    if let Some(caps) = self.regex.captures(&stripped.text) {
+      let group = |name: &str| caps.name(name).map(|m| m.as_str().to_string());
+      let message = caps
+        .name("message")
+        .map(|m| line[stripped.original_range(m.range())].to_string());
+      if let (Some(prefix), Some(message)) = (group("prefix"), message) {
+        self.last_prefix = Some(prefix.clone());
+        res = Line {
+          prefix: Some(prefix),
+          message,
+          // A group named after a severity sets the severity when it matches
+          severity: Severity::ALL
+            .into_iter()
+            .rev()
+            .find(|s| caps.name(s.name()).is_some()),
+          level: group("level"),
+          timestamp: group("timestamp"),
+          source: group("source"),
+          ..Default::default()
+        };
       } else {
         debug!("No prefix found for line: {}", line);
-        res = Some(Line::without_prefix(input.to_string()))
+        res = Line::without_prefix(input.to_string())
       }
       debug!("Parsed line: {:?}", res);
+    } else if let Some(prefix) = self.last_prefix.clone().filter(|_| self.sticky) {
+      res = Line::with_prefix(prefix, line.clone(), None)
     } else {
-      res = Some(Line::without_prefix(input.to_string()))
+      res = Line::without_prefix(input.to_string())
     }
     res
   }
 
-  fn process_error(&mut self, error: &String) {
-    self
-      .error_messages
-      .add_message(Line::without_prefix(error.to_string()));
+  /// Parses a line of output or stderr from a process. Lines matching the continuation regex
+  /// continue the last record of the bucket they were parsed into, or, when they have no prefix,
+  /// the last record of the same stream of the process
+  fn parse_process_line(
+    &mut self,
+    name: Option<&String>,
+    line: &String,
+    stderr: bool,
+  ) -> Option<Line> {
+    let parsed = self.split_process_line(name, line)?;
+    let text = if parsed.prefix.is_some() {
+      &parsed.message
+    } else {
+      line
+    };
+    let plain = Stripped::new(text).text;
+    let key = (name.cloned(), stderr);
+    let target = match &parsed.prefix {
+      Some(prefix) => Some(Some(prefix.clone())),
+      None => self.last_bucket.get(&key).cloned(),
+    };
+    match target.filter(|_| {
+      self
+        .continuation
+        .as_ref()
+        .is_some_and(|continuation| continuation.is_match(&plain))
+    }) {
+      Some(prefix) => Some(Line {
+        prefix,
+        message: text.trim_end().to_string(),
+        severity: parsed.severity,
+        continues: true,
+        stderr,
+        ..Default::default()
+      }),
+      None => {
+        self.last_bucket.insert(key, parsed.prefix.clone());
+        Some(Line { stderr, ..parsed })
+      }
+    }
+  }
+
+  /// Output of named processes always goes in the bucket for the process, or in a sub-bucket if
+  /// `--split` is given and the line matches the regex
+  fn split_process_line(&mut self, name: Option<&String>, line: &String) -> Option<Line> {
+    let Some(name) = name else {
+      return self.parse_line(line);
+    };
+    let severity = self
+      .severity_rules
+      .severity(None, &Stripped::new(line).text);
+    if self.split {
+      if let Some(
+        mut parsed @ Line {
+          prefix: Some(_), ..
+        },
+      ) = self.parse_line(line)
+      {
+        parsed.prefix = parsed.prefix.map(|prefix| format!("{}/{}", name, prefix));
+        return Some(parsed);
+      }
+    }
+    Some(Line::with_prefix(name.clone(), line.clone(), severity))
+  }
+
+  /// Adds a parsed line of output or stderr
+  fn add_received(&mut self, line: &Line) {
+    if line.stderr {
+      self.process_error(line);
+    } else {
+      self.process_line(line);
+    }
+  }
+
+  /// Adds a line written to stderr to the stderr messages, and to its bucket when it has a prefix
+  fn process_error(&mut self, line: &Line) {
+    let line = Line {
+      seq: self.take_seq(),
+      folded: self.fold,
+      ..line.clone()
+    };
+    self.write_error_log(&line);
+    if line.continues {
+      self.error_messages.continue_last(line.clone());
+    } else {
+      self.error_messages.add_message(line.clone());
+    }
+    if line.prefix.is_some() {
+      self.add_to_bucket(line);
+    }
+  }
+
+  /// Writes a parsed line of output or stderr to its log files, without the TUI
+  fn write_received_log(&mut self, line: &Line) {
+    if line.stderr {
+      self.write_error_log(line);
+    } else {
+      self.write_log(Log::for_prefix(line.prefix.as_deref()), line);
+    }
+  }
+
+  /// Writes a stderr line to the stderr log, and to the log of its bucket when it has a prefix
+  fn write_error_log(&mut self, line: &Line) {
+    self.write_log(Log::Stderr, line);
+    if let Some(prefix) = line.prefix.clone() {
+      self.write_log(Log::Bucket(prefix), line);
+    }
+  }
+
+  /// Writes a line to a log file, when `--out-dir` is given
+  fn write_log(&mut self, log: Log, line: &Line) {
+    if let Some(logs) = self.logs.as_mut() {
+      let text = if line.continues {
+        line.message.clone()
+      } else {
+        line.render()
+      };
+      if let Err(e) = logs.write(&log, &text) {
+        debug!("Error writing to log file for {}: {}", log, e);
+      }
+    }
   }
 
   pub fn get_buckets(&self) -> Vec<(&String, &Bucket)> {
     let mut vec = self.buckets.iter().collect::<Vec<_>>();
-    vec.sort_by_key(|(s, _)| s.clone());
+    vec.sort_by_key(|(s, _)| (*s).clone());
     vec
   }
 
@@ -295,23 +1403,51 @@ impl App {
       .and_then(|prefix| self.buckets.get_mut(&prefix))
   }
 
-  pub fn get_current_messages(&mut self, count: usize) -> LinkedList<String> {
-    if self.buckets.len() == 0 {
-      return LinkedList::new();
+  /// Returns the visible messages of the current bucket along with their index in the bucket
+  pub fn get_current_messages(&mut self, rows: usize) -> Vec<(usize, Line)> {
+    if self.buckets.is_empty() {
+      return vec![];
     }
-    let mut bucket = self.get_current_bucket().unwrap();
+    let bucket = self.get_current_bucket().unwrap();
+    let start = bucket.first_visible(rows);
     bucket
-      .get_messages(count - 2)
-      .iter()
-      .map(|l| l.message.clone())
+      .get_messages(rows)
+      .into_iter()
+      .enumerate()
+      .map(|(i, l)| (start + i, l))
+      .collect()
+  }
+
+  /// Returns the visible lines of the merged view of the marked buckets, or all buckets if none are
+  /// marked
+  pub fn get_timeline_messages(&self, rows: usize) -> Vec<Line> {
+    let buckets = timeline_buckets(&self.buckets, &self.marked);
+    self
+      .timeline
+      .get_lines(&buckets, rows)
+      .into_iter()
+      .cloned()
       .collect()
   }
 
+  /// Returns the line that the detail view and folding apply to: the current search match, or the
+  /// newest visible line of the current bucket
+  pub fn get_focused_line(&mut self) -> Option<Line> {
+    let bucket = self.get_current_bucket()?;
+    match bucket.current_match {
+      Some(index) => bucket.get_visible_messages().nth(index).cloned(),
+      None => bucket.get_visible_messages().last().cloned(),
+    }
+  }
+
+  pub fn get_timeline_older(&self) -> usize {
+    let buckets = timeline_buckets(&self.buckets, &self.marked);
+    self.timeline.get_older(&buckets)
+  }
+
   fn open_in_editor(&mut self) -> Option<()> {
-    let mut prefix_name = self.get_selected_prefix()?;
-    let fixed_prefix = Regex::new(r"[@\-/\\:]")
-      .unwrap()
-      .replace_all(&prefix_name, "_");
+    let prefix_name = self.get_selected_prefix()?;
+    let fixed_prefix = sanitize_prefix(&prefix_name);
 
     let log_lines: Vec<String> = self
       .get_current_bucket()?
@@ -324,22 +1460,20 @@ impl App {
     let mut file = OpenOptions::new()
       .write(true)
       .create(true)
+      .truncate(true)
       .open(filename.as_str())
       .ok()?;
     file.write_all(log.as_bytes()).ok()?;
     info!("Wrote log to file {}", filename);
 
     let editor = env::var("EDITOR").ok()?;
-    let mut args = vec![];
-    args.push("-n");
-    args.push(&filename);
+    let args = vec!["-n", &filename];
 
     let mut command = Command::new(editor);
     command.stdout(Stdio::null());
     command.args(args);
     command.spawn().ok()?;
-    //
-    return Some(());
+    Some(())
   }
 
   fn next_bucket(&mut self) {
@@ -349,7 +1483,7 @@ impl App {
     for n in selected + 1..end {
       let i = n % buckets.len();
       let bucket = &buckets[i];
-      if bucket.1.new_errors > 0 {
+      if bucket.1.new_at_least(Severity::Error) > 0 {
         self.list_state.select(Some(i));
         return;
       }
@@ -364,6 +1498,27 @@ impl App {
     }
   }
 
+  /// Drops the oldest lines from the largest buckets until all buckets fit in the memory budget
+  fn enforce_memory_budget(&mut self) {
+    let Some(max_memory) = self.max_memory else {
+      return;
+    };
+    let mut total = self.buckets.values().map(|b| b.bytes).sum::<usize>()
+      + self.error_messages.bytes
+      + self.unprefixed_messages.bytes;
+    while total > max_memory {
+      let largest = self
+        .buckets
+        .values_mut()
+        .chain([&mut self.error_messages, &mut self.unprefixed_messages])
+        .max_by_key(|b| b.bytes);
+      match largest.and_then(|b| b.drop_oldest()) {
+        Some(line) => total -= line.size_in_bytes(),
+        None => break,
+      }
+    }
+  }
+
   fn clear_all_buckets(&mut self) {
     for (_, bucket) in self.buckets.iter_mut() {
       bucket.clear_all_messages();
@@ -1,112 +1,521 @@
 use log::debug;
+use regex::Regex;
 
-use crate::app::Line;
+use std::collections::VecDeque;
+
+use crate::app::{lines_fitting, Line};
+use crate::severity::Severity;
+
+/// Hides the messages of a bucket that don't match (or, when inverted, that do match) a regex
+#[derive(Clone, Debug)]
+pub struct Filter {
+  /// The filter as typed by the user
+  pub text: String,
+  pub regex: Regex,
+  pub invert: bool,
+}
+
+impl Filter {
+  /// Parses a filter typed by the user. A leading `!` inverts the filter. Text that isn't a valid
+  /// regex is matched literally, and the match is case insensitive unless there is an uppercase letter
+  pub fn parse(text: &str) -> Option<Filter> {
+    let (invert, pattern) = match text.strip_prefix('!') {
+      Some(pattern) => (true, pattern),
+      None => (false, text),
+    };
+    if pattern.is_empty() {
+      return None;
+    }
+    let case = if pattern.chars().any(char::is_uppercase) {
+      ""
+    } else {
+      "(?i)"
+    };
+    let regex = Regex::new(&format!("{}{}", case, pattern))
+      .or_else(|_| Regex::new(&format!("{}{}", case, regex::escape(pattern))))
+      .ok()?;
+    Some(Filter {
+      text: text.to_string(),
+      regex,
+      invert,
+    })
+  }
+
+  pub fn matches(&self, line: &Line) -> bool {
+    self.regex.is_match(&line.message) != self.invert
+  }
+}
 
 #[derive(Clone, Debug)]
 pub struct Bucket {
-  messages: Vec<Line>,
+  /// Ring buffer of messages. When `max_lines` is reached, the oldest message is dropped
+  messages: VecDeque<Line>,
+  max_lines: Option<usize>,
+  /// Approximate memory used by the messages
+  pub bytes: usize,
   pub new_messages: usize,
-  pub new_errors: usize,
+  /// Number of new messages of each severity, indexed by `Severity as usize`
+  new_by_severity: [usize; Severity::ALL.len()],
+  /// Index of the first shown message in the filtered view, or `None` to follow new messages
   pub scroll: Option<usize>,
+  /// Index in the filtered view of the search match the user is on
+  pub current_match: Option<usize>,
+  filter: Option<Filter>,
+  /// Least severe messages shown. Messages without a severity count as info
+  min_severity: Option<Severity>,
+  /// Number of messages that pass the filter and the minimum severity
+  view_len: usize,
+  /// Number of restart markers among the messages
+  separators: usize,
 }
 
 impl Bucket {
-  pub fn new() -> Bucket {
+  pub fn new(max_lines: Option<usize>) -> Bucket {
     Bucket {
       messages: Default::default(),
+      max_lines,
+      bytes: 0,
       new_messages: 0,
-      new_errors: 0,
+      new_by_severity: Default::default(),
       scroll: None,
+      current_match: None,
+      filter: None,
+      min_severity: None,
+      view_len: 0,
+      separators: 0,
     }
   }
 
-  pub fn from_messages(messages: Vec<Line>) -> Bucket {
-    Bucket {
-      messages: messages.into_iter().collect(),
-      new_messages: 0,
-      new_errors: 0,
-      scroll: None,
+  pub fn from_messages(messages: Vec<Line>, max_lines: Option<usize>) -> Bucket {
+    let mut bucket = Bucket::new(max_lines);
+    for message in messages {
+      bucket.add_message(message);
     }
+    bucket.reset_new();
+    bucket
+  }
+
+  /// Number of new messages of the severity
+  pub fn new_count(&self, severity: Severity) -> usize {
+    self.new_by_severity[severity as usize]
+  }
+
+  /// Number of new messages of the severity or a more severe one
+  pub fn new_at_least(&self, severity: Severity) -> usize {
+    self.new_by_severity[severity as usize..].iter().sum()
   }
 
-  pub fn get_all_messages(&self) -> &Vec<Line> {
+  fn reset_new(&mut self) {
+    self.new_messages = 0;
+    self.new_by_severity = Default::default();
+  }
+
+  pub fn get_all_messages(&self) -> &VecDeque<Line> {
     &self.messages
   }
 
+  /// Separators are always shown
+  fn is_visible(&self, line: &Line) -> bool {
+    line.separator
+      || self.filter.as_ref().is_none_or(|f| f.matches(line))
+        && self
+          .min_severity
+          .is_none_or(|min| line.severity.unwrap_or(Severity::Info) >= min)
+  }
+
+  /// Messages that pass the filter and the minimum severity
+  pub fn get_visible_messages(&self) -> impl DoubleEndedIterator<Item = &Line> {
+    self.messages.iter().filter(|l| self.is_visible(l))
+  }
+
+  /// Visible messages with a sequence number of at least `seq`
+  pub fn get_visible_from(&self, seq: u64) -> impl Iterator<Item = &Line> {
+    let start = self.messages.partition_point(|l| l.seq < seq);
+    self.messages.range(start..).filter(|l| self.is_visible(l))
+  }
+
+  /// Visible messages with a sequence number below `seq`, newest first
+  pub fn get_visible_before(&self, seq: u64) -> impl Iterator<Item = &Line> {
+    let end = self.messages.partition_point(|l| l.seq < seq);
+    self
+      .messages
+      .range(..end)
+      .rev()
+      .filter(|l| self.is_visible(l))
+  }
+
+  pub fn get_filter(&self) -> Option<&Filter> {
+    self.filter.as_ref()
+  }
+
+  pub fn view_len(&self) -> usize {
+    self.view_len
+  }
+
+  /// Sets the filter, resetting the scroll position since indices into the view change
+  pub fn set_filter(&mut self, filter: Option<Filter>) {
+    self.filter = filter;
+    self.update_view();
+  }
+
+  pub fn get_min_severity(&self) -> Option<Severity> {
+    self.min_severity
+  }
+
+  /// Sets the least severe messages shown, resetting the scroll position like `set_filter`
+  pub fn set_min_severity(&mut self, min_severity: Option<Severity>) {
+    self.min_severity = min_severity;
+    self.update_view();
+  }
+
+  fn update_view(&mut self) {
+    self.view_len = self.get_visible_messages().count();
+    self.scroll = None;
+    self.current_match = None;
+  }
+
   pub fn add_message(&mut self, message: Line) {
-    self.new_errors += if message.has_error { 1 } else { 0 };
-    self.new_messages += 1;
-    self.messages.push(message);
+    if message.separator {
+      self.separators += 1;
+    } else {
+      if let Some(severity) = message.severity {
+        self.new_by_severity[severity as usize] += 1;
+      }
+      self.new_messages += 1;
+    }
+    self.bytes += message.size_in_bytes();
+    if self.is_visible(&message) {
+      self.view_len += 1;
+    }
+    self.messages.push_back(message);
+    if let Some(max_lines) = self.max_lines {
+      while self.messages.len() > max_lines {
+        self.drop_oldest();
+      }
+    }
+  }
+
+  /// Appends a continuation line to the last message, or adds it as a message if there is none or
+  /// it was written to the other one of stdout and stderr
+  pub fn continue_last(&mut self, line: Line) {
+    let Some(last) = self
+      .messages
+      .back()
+      .filter(|last| last.stderr == line.stderr && !last.separator)
+    else {
+      return self.add_message(line);
+    };
+    let was_visible = self.is_visible(last);
+    // The last record is among the new ones unless they were seen since it was added
+    let is_new = self.new_messages > 0;
+    let last = self.messages.back_mut().unwrap();
+    last.message.push('\n');
+    last.message.push_str(&line.message);
+    // The record is as severe as its most severe line
+    if line.severity > last.severity {
+      if is_new {
+        if let Some(previous) = last.severity {
+          let count = &mut self.new_by_severity[previous as usize];
+          *count = count.saturating_sub(1);
+        }
+        self.new_by_severity[line.severity.unwrap() as usize] += 1;
+      }
+      last.severity = line.severity;
+    }
+    self.bytes += line.message.len() + 1;
+    match (was_visible, self.is_visible(self.messages.back().unwrap())) {
+      (false, true) => self.view_len += 1,
+      (true, false) => self.view_len -= 1,
+      _ => {}
+    }
+  }
+
+  pub fn toggle_fold(&mut self, seq: u64) {
+    let index = self.messages.partition_point(|l| l.seq < seq);
+    if let Some(line) = self.messages.get_mut(index).filter(|l| l.seq == seq) {
+      line.folded = !line.folded;
+    }
+  }
+
+  pub fn fold_all(&mut self, folded: bool) {
+    for line in self.messages.iter_mut() {
+      line.folded = folded;
+    }
+  }
+
+  /// Drops the oldest message, keeping the scroll position on the same messages and the new message
+  /// counters within the messages that are left
+  pub fn drop_oldest(&mut self) -> Option<Line> {
+    let line = self.messages.pop_front()?;
+    self.bytes -= line.size_in_bytes();
+    if self.is_visible(&line) {
+      self.view_len -= 1;
+      self.scroll = self.scroll.map(|scroll| scroll.saturating_sub(1));
+      self.current_match = self.current_match.and_then(|i| i.checked_sub(1));
+    }
+    if line.separator {
+      self.separators -= 1;
+    } else if self.new_messages > self.messages.len() - self.separators {
+      self.new_messages -= 1;
+      if let Some(severity) = line.severity {
+        let count = &mut self.new_by_severity[severity as usize];
+        *count = count.saturating_sub(1);
+      }
+    }
+    Some(line)
   }
 
   pub fn clear_all_messages(&mut self) {
     self.messages.clear();
-    self.new_messages = 0;
-    self.new_errors = 0;
+    self.view_len = 0;
+    self.separators = 0;
+    self.bytes = 0;
+    self.reset_new();
+    self.scroll = None;
+    self.current_match = None;
   }
 
-  pub fn get_older(&self, height: usize) -> usize {
+  /// Number of messages below the ones shown in `rows` rows
+  pub fn get_older(&self, rows: usize) -> usize {
     if let Some(scroll) = self.scroll {
       debug!(
-        "get_older: scroll = {}, height = {}, msgs: {}",
-        scroll,
-        height,
-        self.messages.len()
+        "get_older: scroll = {}, rows = {}, msgs: {}",
+        scroll, rows, self.view_len
       );
-      self.messages.len().max(scroll + height) - scroll - height
+      self.view_len - scroll - self.fitting_from(scroll, rows)
     } else {
       0
     }
   }
 
-  pub fn get_messages(&mut self, count: usize) -> Vec<Line> {
-    self.new_messages = 0;
-    self.new_errors = 0;
+  /// Number of messages from the one at `index` that fit in `rows` rows
+  fn fitting_from(&self, index: usize, rows: usize) -> usize {
+    lines_fitting(self.get_visible_messages().skip(index), rows)
+  }
 
-    let skip = self
+  /// Index of the first message shown in `rows` rows
+  pub fn first_visible(&self, rows: usize) -> usize {
+    self
       .scroll
-      .unwrap_or((self.messages.len() as i32 - count as i32).max(0) as usize);
+      .unwrap_or_else(|| self.view_len - lines_fitting(self.get_visible_messages().rev(), rows))
+  }
+
+  /// Returns the messages shown in `rows` rows. Messages with several lines take several rows
+  pub fn get_messages(&mut self, rows: usize) -> Vec<Line> {
+    self.reset_new();
+
+    let skip = self.first_visible(rows);
 
     debug!("scroll: {:?}", self.scroll);
     debug!("skip: {}", skip);
 
+    let count = lines_fitting(self.get_visible_messages().skip(skip), rows);
     self
-      .messages
-      .iter()
+      .get_visible_messages()
       .skip(skip)
       .take(count)
       .cloned()
       .collect()
   }
 
-  pub fn scroll_up(&mut self, height: usize) {
-    if self.messages.len() < height + 1 {
-      return;
-    }
-    self.scroll = if let Some(scroll) = self.scroll {
-      if scroll == 0 {
-        Some(0)
-      } else {
-        Some(scroll - 1)
-      }
-    } else {
-      Some(self.messages.len() - height - 1)
-    };
+  /// Indices in the filtered view of the messages matching the search
+  pub fn find_matches(&self, search: &Regex) -> Vec<usize> {
+    self
+      .get_visible_messages()
+      .enumerate()
+      .filter(|(_, line)| search.is_match(&line.message))
+      .map(|(i, _)| i)
+      .collect()
   }
 
-  pub fn scroll_down(&mut self, height: usize) {
-    self.scroll = if let Some(scroll) = self.scroll {
-      if scroll + height >= self.messages.len() {
-        None
-      } else {
-        Some(scroll + 1)
-      }
-    } else {
+  /// Indices in the filtered view and sequence numbers of the messages of at least the severity
+  pub fn find_severity(&self, min: Severity) -> Vec<(usize, u64)> {
+    self
+      .get_visible_messages()
+      .enumerate()
+      .filter(|(_, line)| line.severity.is_some_and(|s| s >= min))
+      .map(|(i, line)| (i, line.seq))
+      .collect()
+  }
+
+  /// Scrolls so the message at `index` is in the middle of `rows` rows
+  pub fn scroll_to(&mut self, index: usize, rows: usize) {
+    let mut used = 0;
+    let above = self
+      .get_visible_messages()
+      .rev()
+      .skip(self.view_len - index)
+      .take_while(|line| {
+        used += line.height();
+        used <= rows / 2
+      })
+      .count();
+    let scroll = index - above;
+    self.scroll = if scroll + self.fitting_from(scroll, rows) >= self.view_len {
       None
+    } else {
+      Some(scroll)
     };
   }
 
+  pub fn scroll_up(&mut self, rows: usize) {
+    if self.first_visible(rows) == 0 {
+      return;
+    }
+    self.scroll = Some(self.first_visible(rows) - 1);
+  }
+
+  pub fn scroll_down(&mut self, rows: usize) {
+    self.scroll = self
+      .scroll
+      .filter(|&scroll| scroll + self.fitting_from(scroll, rows) < self.view_len)
+      .map(|scroll| scroll + 1);
+  }
+
   pub fn scroll_reset(&mut self) {
     self.scroll = None;
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn line(seq: u64, message: &str) -> Line {
+    Line {
+      message: message.to_string(),
+      seq,
+      ..Default::default()
+    }
+  }
+
+  /// A bucket of 20 messages where those before `tall` take 5 rows each
+  fn bucket(tall: u64) -> Bucket {
+    let mut bucket = Bucket::new(None);
+    for seq in 0..20 {
+      let message = if seq < tall { "a\nb\nc\nd\ne" } else { "a" };
+      bucket.add_message(line(seq, message));
+    }
+    bucket
+  }
+
+  fn shown(bucket: &mut Bucket, rows: usize) -> Vec<u64> {
+    bucket.get_messages(rows).iter().map(|l| l.seq).collect()
+  }
+
+  fn filter_matches(filter: &str, message: &str) -> bool {
+    Filter::parse(filter).unwrap().matches(&line(0, message))
+  }
+
+  #[test]
+  fn filters_are_regexes() {
+    assert!(filter_matches("err(or)?s?$", "2 errors"));
+    assert!(!filter_matches("^err", "2 errors"));
+  }
+
+  #[test]
+  fn filters_starting_with_a_bang_are_inverted() {
+    assert!(!filter_matches("!error", "an error"));
+    assert!(filter_matches("!error", "all good"));
+    assert!(Filter::parse("!error").unwrap().invert);
+  }
+
+  #[test]
+  fn empty_filters_are_none() {
+    assert!(Filter::parse("").is_none());
+    assert!(Filter::parse("!").is_none());
+  }
+
+  #[test]
+  fn invalid_regexes_are_matched_literally() {
+    assert!(filter_matches("foo(", "call foo(1)"));
+    assert!(!filter_matches("foo(", "call foo"));
+    assert!(filter_matches("!foo(", "call foo"));
+  }
+
+  #[test]
+  fn filters_are_case_insensitive_without_uppercase() {
+    assert!(filter_matches("error", "ERROR: failed"));
+    assert!(filter_matches("ERROR", "ERROR: failed"));
+    assert!(!filter_matches("Error", "ERROR: failed"));
+  }
+
+  #[test]
+  fn filters_hide_messages_from_the_view() {
+    let mut bucket = Bucket::new(None);
+    for (seq, message) in ["error: a", "ok", "error: b"].into_iter().enumerate() {
+      bucket.add_message(line(seq as u64, message));
+    }
+    bucket.set_filter(Filter::parse("!error"));
+    assert_eq!(shown(&mut bucket, 10), vec![1]);
+    bucket.set_filter(None);
+    assert_eq!(shown(&mut bucket, 10), vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn continuation_counts_severity_only_for_new_records() {
+    let mut bucket = Bucket::new(None);
+    bucket.add_message(line(0, "starting"));
+    let error = Line {
+      severity: Some(Severity::Error),
+      ..line(1, "failed")
+    };
+    bucket.continue_last(error.clone());
+    assert_eq!(bucket.new_count(Severity::Error), 1);
+    bucket.add_message(line(2, "retrying"));
+    bucket.get_messages(10);
+    bucket.continue_last(error);
+    assert_eq!(bucket.new_count(Severity::Error), 0);
+    assert_eq!(bucket.new_messages, 0);
+  }
+
+  #[test]
+  fn dropping_the_oldest_keeps_counts_and_positions() {
+    let mut bucket = Bucket::new(Some(3));
+    bucket.add_message(line(0, "old"));
+    bucket.add_message(Line {
+      separator: true,
+      ..line(1, "restart")
+    });
+    bucket.add_message(line(2, "new"));
+    bucket.scroll = Some(1);
+    bucket.current_match = Some(2);
+    bucket.add_message(line(3, "newer"));
+    assert_eq!(bucket.new_messages, 2);
+    assert_eq!(bucket.scroll, Some(0));
+    assert_eq!(bucket.current_match, Some(1));
+    bucket.add_message(line(4, "newest"));
+    assert_eq!(bucket.new_messages, 3);
+    assert_eq!(bucket.scroll, Some(0));
+    assert_eq!(bucket.current_match, Some(0));
+    bucket.add_message(line(5, "latest"));
+    assert_eq!(bucket.new_messages, 3);
+    assert_eq!(bucket.current_match, None);
+  }
+
+  #[test]
+  fn scroll_to_shows_target_below_multi_line_records() {
+    let mut bucket = bucket(10);
+    bucket.scroll_to(10, 10);
+    assert_eq!(shown(&mut bucket, 10), (9..15).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn scroll_to_follows_when_the_end_fits() {
+    let mut bucket = bucket(10);
+    bucket.scroll_to(18, 10);
+    assert_eq!(bucket.scroll, None);
+  }
+
+  #[test]
+  fn scrolls_by_records_of_several_rows() {
+    let mut bucket = bucket(20);
+    bucket.scroll_up(10);
+    assert_eq!(shown(&mut bucket, 10), vec![17, 18]);
+    assert_eq!(bucket.get_older(10), 1);
+    bucket.scroll_down(10);
+    assert_eq!(shown(&mut bucket, 10), vec![18, 19]);
+    bucket.scroll_down(10);
+    assert_eq!(bucket.scroll, None);
+  }
+}
@@ -1,13 +1,320 @@
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::builder::PossibleValuesParser;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::Deserialize;
 
-const TURBO_REGEX: &str = r"^(?P<prefix>\S*?):(?P<rest> .*)";
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::config_file::ConfigFile;
+use crate::presets;
+use crate::structured::FieldNames;
+
+pub const TURBO_REGEX: &str = r"^(?P<prefix>\S*?): (?P<message>.*)";
+/// `--continuation` value for continuing records with indented lines
+pub const INDENT: &str = "indent";
+pub const INDENT_REGEX: &str = r"^\s+\S";
+
+/// How lines are split into prefix, message and the other parts of a `Line`
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+  /// With the prefix regex
+  #[default]
+  Regex,
+  /// As JSON objects, using the `--*-field` options
+  Json,
+  /// As logfmt `key=value` pairs, using the `--*-field` options
+  Logfmt,
+}
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
-  /// Regex for the prefix
+  /// Regex for the prefix. Must have `prefix` and `message` named groups, and may have `level`,
+  /// `timestamp` and `source` groups
   #[arg(short, long, default_value_t = TURBO_REGEX.to_string())]
   pub prefix: String,
 
+  /// Built-in prefix regex for a common tool, or `auto` to pick one based on the first lines of
+  /// output
+  #[arg(long, conflicts_with = "prefix", value_parser = PossibleValuesParser::new(presets::names()))]
+  pub preset: Option<String>,
+
+  /// How to parse lines
+  #[arg(long, value_enum, default_value_t = Format::Regex)]
+  pub format: Format,
+
+  /// Field holding the prefix, for structured formats. Nested JSON fields are separated by dots, e.g.
+  /// `kubernetes.pod`
+  #[arg(long, value_name = "FIELD", default_value = "service")]
+  pub prefix_field: String,
+
+  /// Field holding the message, for structured formats
+  #[arg(long, value_name = "FIELD", default_value = "msg")]
+  pub message_field: String,
+
+  /// Field holding the level, for structured formats
+  #[arg(long, value_name = "FIELD", default_value = "level")]
+  pub level_field: String,
+
+  /// Field holding the timestamp, for structured formats
+  #[arg(long, value_name = "FIELD", default_value = "time")]
+  pub timestamp_field: String,
+
+  /// Regex for lines that continue the previous record, like the lines of a stack trace, or
+  /// `indent` for lines that start with whitespace. The message is matched for lines with a prefix
+  #[arg(long, value_name = "REGEX")]
+  pub continuation: Option<String>,
+
+  /// Start with multi-line records folded to their first line. Continues records with indented
+  /// lines unless `--continuation` is given
+  #[arg(long)]
+  pub fold: bool,
+
+  /// Regex for lines of a severity without a known level, e.g. `--severity 'warn=deprecated'`.
+  /// Severities are trace, debug, info, warn, error and fatal. Can be given multiple times
+  #[arg(long = "severity", value_name = "SEVERITY=REGEX")]
+  pub severities: Vec<String>,
+
+  /// Profile from `prism.toml` or `~/.config/prism/config.toml` to use
+  #[arg(long)]
+  pub profile: Option<String>,
+
+  /// Named command to run, e.g. `-c 'api: cargo run -p api'`. Can be given multiple times
+  #[arg(short = 'c', long = "command", value_name = "NAME: COMMAND")]
+  pub commands: Vec<String>,
+
+  /// Also split the output of each named command using the prefix regex
+  #[arg(short, long)]
+  pub split: bool,
+
+  /// Maximum number of lines to keep per bucket. The oldest lines are dropped first. 0 means no limit
+  #[arg(long, default_value_t = 10000)]
+  pub max_lines: usize,
+
+  /// Maximum memory in MB to use for lines across all buckets. The oldest lines of the largest
+  /// buckets are dropped first
+  #[arg(long, value_name = "MB")]
+  pub max_memory: Option<usize>,
+
+  /// Run without the TUI, passing the output through and writing each bucket to a file in
+  /// `--out-dir`
+  #[arg(long, requires = "out_dir")]
+  pub no_tui: bool,
+
+  /// Directory to write each bucket to as `<prefix>.log`, along with `stderr.log` and
+  /// `unparsed.log`
+  #[arg(long, value_name = "DIR")]
+  pub out_dir: Option<PathBuf>,
+
+  /// Size in MB at which a log file in `--out-dir` is rotated. 0 means no rotation
+  #[arg(long, value_name = "MB", default_value_t = 10)]
+  pub rotate_size: u64,
+
+  /// Number of rotated log files to keep per bucket
+  #[arg(long, value_name = "COUNT", default_value_t = 5)]
+  pub rotate_keep: usize,
+
+  /// Signals sent to the process group of each command on quit, each with the seconds to wait for
+  /// the group to exit before sending the next
+  #[arg(
+    long,
+    value_name = "SIGNAL:SECONDS,...",
+    default_value = "SIGINT:3,SIGTERM:3,SIGKILL"
+  )]
+  pub shutdown: String,
+
+  /// Clear all buckets when the commands are restarted, instead of keeping their output above a
+  /// restart marker
+  #[arg(long)]
+  pub clear_on_restart: bool,
+
+  /// Quit once all commands have exited, instead of keeping the TUI open to look at their output.
+  /// Ignored with `--watch`
+  #[arg(long)]
+  pub auto_exit: bool,
+
+  /// Run the commands in a pseudo-terminal, so they keep their colors and progress output. Their
+  /// stderr can't be told apart from their stdout then
+  #[arg(long)]
+  pub pty: bool,
+
+  /// Size of the pseudo-terminal with `--pty`, e.g. `120x40`. By default it follows the size of
+  /// the message pane
+  #[arg(long, value_name = "COLUMNSxROWS")]
+  pub pty_size: Option<String>,
+
+  /// Files or directories to watch. The commands are restarted when a file in them changes. Can be
+  /// given multiple times or separated by commas
+  #[arg(long, value_name = "PATH", value_delimiter = ',')]
+  pub watch: Vec<PathBuf>,
+
+  /// Only restart for changed files matching one of these globs, e.g. `**/*.rs`. Can be given
+  /// multiple times
+  #[arg(long, value_name = "GLOB")]
+  pub watch_include: Vec<String>,
+
+  /// Don't restart for changed files matching one of these globs. Can be given multiple times
+  #[arg(
+    long,
+    value_name = "GLOB",
+    default_values = ["**/.git/**", "**/target/**", "**/node_modules/**"]
+  )]
+  pub watch_exclude: Vec<String>,
+
+  /// Milliseconds to wait after a file changes for more changes before restarting
+  #[arg(long, value_name = "MS", default_value_t = 300)]
+  pub watch_debounce: u64,
+
   /// Command to run
   pub command: Vec<String>,
+
+  /// Regexes by severity, from the config files and `--severity`
+  #[arg(skip)]
+  pub severity_rules: HashMap<String, String>,
+
+  /// Keys by action name, from the config files
+  #[arg(skip)]
+  pub keys: HashMap<String, String>,
+
+  /// Colors by theme element, from the config files
+  #[arg(skip)]
+  pub colors: HashMap<String, String>,
+}
+
+impl Config {
+  /// Parses the command line, filling in the options that weren't given on it from the config
+  /// files
+  pub fn load() -> Result<Config> {
+    let matches = Config::command().get_matches();
+    let mut config = Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let settings = ConfigFile::load()?.settings_for(config.profile.as_deref())?;
+    let from_file = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+    if let Some(prefix) = settings.prefix.filter(|_| from_file("prefix")) {
+      config.prefix = prefix;
+    }
+    // A prefix given on the command line takes precedence over a preset from the config files
+    if let Some(preset) = settings
+      .preset
+      .filter(|_| from_file("preset") && from_file("prefix"))
+    {
+      config.preset = Some(preset);
+    }
+    if let Some(format) = settings.format.filter(|_| from_file("format")) {
+      config.format = format;
+    }
+    if let Some(field) = settings.prefix_field.filter(|_| from_file("prefix_field")) {
+      config.prefix_field = field;
+    }
+    if let Some(field) = settings
+      .message_field
+      .filter(|_| from_file("message_field"))
+    {
+      config.message_field = field;
+    }
+    if let Some(field) = settings.level_field.filter(|_| from_file("level_field")) {
+      config.level_field = field;
+    }
+    if let Some(field) = settings
+      .timestamp_field
+      .filter(|_| from_file("timestamp_field"))
+    {
+      config.timestamp_field = field;
+    }
+    if let Some(continuation) = settings.continuation.filter(|_| from_file("continuation")) {
+      config.continuation = Some(continuation);
+    }
+    if let Some(fold) = settings.fold.filter(|_| from_file("fold")) {
+      config.fold = fold;
+    }
+    // Without a continuation regex every record is a single line, so there would be nothing to fold
+    if config.fold && config.continuation.is_none() {
+      config.continuation = Some(INDENT.to_string());
+    }
+    if let Some(shutdown) = settings.shutdown.filter(|_| from_file("shutdown")) {
+      config.shutdown = shutdown;
+    }
+    if let Some(clear_on_restart) = settings
+      .clear_on_restart
+      .filter(|_| from_file("clear_on_restart"))
+    {
+      config.clear_on_restart = clear_on_restart;
+    }
+    if let Some(auto_exit) = settings.auto_exit.filter(|_| from_file("auto_exit")) {
+      config.auto_exit = auto_exit;
+    }
+    if let Some(pty) = settings.pty.filter(|_| from_file("pty")) {
+      config.pty = pty;
+    }
+    if let Some(size) = settings.pty_size.filter(|_| from_file("pty_size")) {
+      config.pty_size = Some(size);
+    }
+    if let Some(watch) = settings.watch.filter(|_| from_file("watch")) {
+      config.watch = watch;
+    }
+    if let Some(include) = settings
+      .watch_include
+      .filter(|_| from_file("watch_include"))
+    {
+      config.watch_include = include;
+    }
+    if let Some(exclude) = settings
+      .watch_exclude
+      .filter(|_| from_file("watch_exclude"))
+    {
+      config.watch_exclude = exclude;
+    }
+    if let Some(debounce) = settings
+      .watch_debounce
+      .filter(|_| from_file("watch_debounce"))
+    {
+      config.watch_debounce = debounce;
+    }
+    if let Some(max_lines) = settings.max_lines.filter(|_| from_file("max_lines")) {
+      config.max_lines = max_lines;
+    }
+    if let Some(max_memory) = settings.max_memory.filter(|_| from_file("max_memory")) {
+      config.max_memory = Some(max_memory);
+    }
+    config.severity_rules = settings.severity;
+    for rule in &config.severities {
+      let (severity, regex) = rule
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid severity rule `{}`, expected SEVERITY=REGEX", rule))?;
+      config
+        .severity_rules
+        .insert(severity.trim().to_string(), regex.to_string());
+    }
+    config.keys = settings.keys;
+    config.colors = settings.colors;
+    Ok(config)
+  }
+
+  pub fn field_names(&self) -> FieldNames {
+    FieldNames {
+      prefix: self.prefix_field.clone(),
+      message: self.message_field.clone(),
+      level: self.level_field.clone(),
+      timestamp: self.timestamp_field.clone(),
+    }
+  }
+
+  pub fn max_lines(&self) -> Option<usize> {
+    Some(self.max_lines).filter(|&max_lines| max_lines > 0)
+  }
+
+  /// Returns the `--command` arguments as `(name, command)` pairs
+  pub fn named_commands(&self) -> Option<Vec<(String, String)>> {
+    self
+      .commands
+      .iter()
+      .map(|c| {
+        c.split_once(':')
+          .map(|(name, command)| (name.trim().to_string(), command.trim().to_string()))
+          .filter(|(name, command)| !name.is_empty() && !command.is_empty())
+      })
+      .collect()
+  }
 }
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::cli::Format;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// Name of the project-local config file, looked up in the current directory and its parents
+const PROJECT_CONFIG: &str = "prism.toml";
+
+/// Settings that can be given in a config file, either at the top level or in a profile
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+  pub prefix: Option<String>,
+  /// Name of a built-in prefix regex, taking precedence over `prefix`
+  pub preset: Option<String>,
+  pub format: Option<Format>,
+  pub prefix_field: Option<String>,
+  pub message_field: Option<String>,
+  pub level_field: Option<String>,
+  pub timestamp_field: Option<String>,
+  pub continuation: Option<String>,
+  pub fold: Option<bool>,
+  pub max_lines: Option<usize>,
+  pub max_memory: Option<usize>,
+  pub shutdown: Option<String>,
+  pub clear_on_restart: Option<bool>,
+  pub auto_exit: Option<bool>,
+  pub pty: Option<bool>,
+  pub pty_size: Option<String>,
+  pub watch: Option<Vec<PathBuf>>,
+  pub watch_include: Option<Vec<String>>,
+  pub watch_exclude: Option<Vec<String>>,
+  pub watch_debounce: Option<u64>,
+  /// Regexes by severity, e.g. `warn = "deprecated"`
+  pub severity: HashMap<String, String>,
+  /// Keys by action name, e.g. `scroll-up = "w K"`
+  pub keys: HashMap<String, String>,
+  /// Colors by theme element, e.g. `title = "green"`
+  pub colors: HashMap<String, String>,
+}
+
+impl Settings {
+  /// Returns these settings with the ones given in `other` taking precedence
+  fn merge(mut self, other: Settings) -> Settings {
+    // A prefix or preset replaces both the prefix and the preset of less specific settings
+    if other.prefix.is_some() || other.preset.is_some() {
+      self.prefix = other.prefix;
+      self.preset = other.preset;
+    }
+    self.format = other.format.or(self.format);
+    self.prefix_field = other.prefix_field.or(self.prefix_field);
+    self.message_field = other.message_field.or(self.message_field);
+    self.level_field = other.level_field.or(self.level_field);
+    self.timestamp_field = other.timestamp_field.or(self.timestamp_field);
+    self.continuation = other.continuation.or(self.continuation);
+    self.fold = other.fold.or(self.fold);
+    self.max_lines = other.max_lines.or(self.max_lines);
+    self.max_memory = other.max_memory.or(self.max_memory);
+    self.shutdown = other.shutdown.or(self.shutdown);
+    self.clear_on_restart = other.clear_on_restart.or(self.clear_on_restart);
+    self.auto_exit = other.auto_exit.or(self.auto_exit);
+    self.pty = other.pty.or(self.pty);
+    self.pty_size = other.pty_size.or(self.pty_size);
+    self.watch = other.watch.or(self.watch);
+    self.watch_include = other.watch_include.or(self.watch_include);
+    self.watch_exclude = other.watch_exclude.or(self.watch_exclude);
+    self.watch_debounce = other.watch_debounce.or(self.watch_debounce);
+    self.severity.extend(other.severity);
+    self.keys.extend(other.keys);
+    self.colors.extend(other.colors);
+    self
+  }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+  #[serde(flatten)]
+  pub settings: Settings,
+  /// Named profiles, selected with `--profile`
+  pub profile: HashMap<String, Settings>,
+}
+
+impl ConfigFile {
+  fn read(path: &Path) -> Result<ConfigFile> {
+    let contents =
+      fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+  }
+
+  fn merge(mut self, other: ConfigFile) -> ConfigFile {
+    self.settings = self.settings.merge(other.settings);
+    for (name, profile) in other.profile {
+      let merged = self
+        .profile
+        .remove(&name)
+        .unwrap_or_default()
+        .merge(profile);
+      self.profile.insert(name, merged);
+    }
+    self
+  }
+
+  /// Loads the user config file and the project config file, with the project config taking
+  /// precedence
+  pub fn load() -> Result<ConfigFile> {
+    [user_config_path(), project_config_path()]
+      .into_iter()
+      .flatten()
+      .filter(|path| path.is_file())
+      .try_fold(ConfigFile::default(), |config, path| {
+        Ok(config.merge(ConfigFile::read(&path)?))
+      })
+  }
+
+  /// Returns the top level settings, overridden by the given profile
+  pub fn settings_for(self, profile: Option<&str>) -> Result<Settings> {
+    let Some(name) = profile else {
+      return Ok(self.settings);
+    };
+    let mut profiles = self.profile;
+    let profile = profiles
+      .remove(name)
+      .ok_or_else(|| anyhow!("No profile named `{}` in the config files", name))?;
+    Ok(self.settings.merge(profile))
+  }
+}
+
+/// `$XDG_CONFIG_HOME/prism/config.toml`, or `~/.config/prism/config.toml`
+fn user_config_path() -> Option<PathBuf> {
+  let config_home = env::var_os("XDG_CONFIG_HOME")
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+  Some(config_home.join("prism").join("config.toml"))
+}
+
+/// The closest `prism.toml` in the current directory or its parents
+fn project_config_path() -> Option<PathBuf> {
+  let cwd = env::current_dir().ok()?;
+  cwd
+    .ancestors()
+    .map(|dir| dir.join(PROJECT_CONFIG))
+    .find(|path| path.is_file())
+}
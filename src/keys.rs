@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+use crossterm::event::KeyCode;
+
+use std::collections::HashMap;
+
+/// Something the user can do in the TUI. Actions are bound to keys in a `KeyMap`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+  Quit,
+  NextPrefix,
+  PreviousPrefix,
+  ScrollUp,
+  ScrollDown,
+  ScrollReset,
+  /// Clears the search and goes back to the messages of the selected bucket
+  Back,
+  ShowErrors,
+  ShowParseErrors,
+  ShowTimeline,
+  /// Shows the whole structured object of the current match, or of the newest line
+  ShowDetail,
+  ToggleMarked,
+  /// Cycles the least severe messages shown in the current bucket through all, info and up, warn
+  /// and up and error and up
+  CycleLevel,
+  /// Cycles the least severe messages shown in all buckets
+  CycleGlobalLevel,
+  /// Folds or unfolds all multi-line records
+  FoldAll,
+  /// Folds or unfolds the current match, or the newest record
+  ToggleFold,
+  Search,
+  Filter,
+  /// Jumps to the next search match, or to the next bucket with new messages when not searching
+  NextMatch,
+  PreviousMatch,
+  /// Jumps to the next error line, across buckets
+  NextError,
+  PreviousError,
+  ClearBucket,
+  ClearAllBuckets,
+  OpenInEditor,
+  /// Stops the commands and runs them again
+  Restart,
+}
+
+/// Action names used in the `[keys]` section of the config file, with their default keys
+const ACTIONS: &[(&str, Action, &str)] = &[
+  ("quit", Action::Quit, "q"),
+  ("next-prefix", Action::NextPrefix, "j"),
+  ("previous-prefix", Action::PreviousPrefix, "k"),
+  ("scroll-up", Action::ScrollUp, "w K"),
+  ("scroll-down", Action::ScrollDown, "s J"),
+  ("scroll-reset", Action::ScrollReset, "r"),
+  ("back", Action::Back, "esc"),
+  ("show-errors", Action::ShowErrors, "e"),
+  ("show-parse-errors", Action::ShowParseErrors, "p"),
+  ("show-timeline", Action::ShowTimeline, "a"),
+  ("show-detail", Action::ShowDetail, "d"),
+  ("toggle-marked", Action::ToggleMarked, "space"),
+  ("cycle-level", Action::CycleLevel, "l"),
+  ("cycle-global-level", Action::CycleGlobalLevel, "L"),
+  ("fold-all", Action::FoldAll, "z"),
+  ("toggle-fold", Action::ToggleFold, "o"),
+  ("search", Action::Search, "/"),
+  ("filter", Action::Filter, "f"),
+  ("next-match", Action::NextMatch, "n"),
+  ("previous-match", Action::PreviousMatch, "N"),
+  ("next-error", Action::NextError, "]"),
+  ("previous-error", Action::PreviousError, "["),
+  ("clear-bucket", Action::ClearBucket, "c"),
+  ("clear-all-buckets", Action::ClearAllBuckets, "C"),
+  ("open-in-editor", Action::OpenInEditor, "enter"),
+  ("restart", Action::Restart, "R"),
+];
+
+/// Parses a key like `q`, `K`, `space` or `enter`
+fn parse_key(key: &str) -> Option<KeyCode> {
+  let mut chars = key.chars();
+  if let (Some(c), None) = (chars.next(), chars.next()) {
+    return Some(KeyCode::Char(c));
+  }
+  Some(match key.to_lowercase().as_str() {
+    "space" => KeyCode::Char(' '),
+    "enter" => KeyCode::Enter,
+    "esc" => KeyCode::Esc,
+    "tab" => KeyCode::Tab,
+    "backspace" => KeyCode::Backspace,
+    "up" => KeyCode::Up,
+    "down" => KeyCode::Down,
+    "left" => KeyCode::Left,
+    "right" => KeyCode::Right,
+    "pageup" => KeyCode::PageUp,
+    "pagedown" => KeyCode::PageDown,
+    "home" => KeyCode::Home,
+    "end" => KeyCode::End,
+    _ => return None,
+  })
+}
+
+/// Parses space separated keys
+fn parse_keys(keys: &str) -> Result<Vec<KeyCode>> {
+  keys
+    .split_whitespace()
+    .map(|key| parse_key(key).ok_or_else(|| anyhow!("Unknown key `{}`", key)))
+    .collect()
+}
+
+pub struct KeyMap {
+  bindings: HashMap<KeyCode, Action>,
+}
+
+impl KeyMap {
+  /// Creates the default key map, with the keys of the actions in `overrides` replaced
+  pub fn new(overrides: &HashMap<String, String>) -> Result<KeyMap> {
+    let mut bindings = HashMap::new();
+    // Overridden keys are bound last, so they take precedence over default keys of other actions
+    for (_, action, keys) in ACTIONS
+      .iter()
+      .filter(|(n, _, _)| !overrides.contains_key(*n))
+    {
+      for key in parse_keys(keys)? {
+        bindings.insert(key, *action);
+      }
+    }
+    for (name, action, _) in ACTIONS
+      .iter()
+      .filter(|(n, _, _)| overrides.contains_key(*n))
+    {
+      for key in parse_keys(&overrides[*name])? {
+        bindings.insert(key, *action);
+      }
+    }
+    if let Some(name) = overrides
+      .keys()
+      .find(|name| !ACTIONS.iter().any(|(n, _, _)| n == name))
+    {
+      return Err(anyhow!("Unknown action `{}` in [keys]", name));
+    }
+    Ok(KeyMap { bindings })
+  }
+
+  pub fn get(&self, key: KeyCode) -> Option<Action> {
+    self.bindings.get(&key).copied()
+  }
+}
@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Name of the file for the lines written to stderr
+const STDERR_LOG: &str = "stderr";
+/// Name of the file for the lines that didn't match the prefix regex
+const UNPARSED_LOG: &str = "unparsed";
+/// Name of the file for an empty prefix, which would otherwise be the hidden `.log`
+const EMPTY_PREFIX: &str = "empty";
+
+/// Characters that can't be in a file name or would make it awkward to use in a shell
+static UNSAFE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[@\-/\\:]").unwrap());
+
+/// A log file in the log directory: one per bucket, and one each for the stderr and unparsable
+/// lines
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Log {
+  Bucket(String),
+  Stderr,
+  Unparsed,
+}
+
+impl Log {
+  /// The log of a bucket, or the unparsed log for lines without a prefix
+  pub fn for_prefix(prefix: Option<&str>) -> Log {
+    prefix.map_or(Log::Unparsed, |prefix| Log::Bucket(prefix.to_string()))
+  }
+}
+
+impl std::fmt::Display for Log {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Log::Bucket(prefix) => write!(f, "{}", prefix),
+      Log::Stderr => write!(f, "{}", STDERR_LOG),
+      Log::Unparsed => write!(f, "{}", UNPARSED_LOG),
+    }
+  }
+}
+
+/// Turns a prefix into something that can be used as a file name
+pub fn sanitize_prefix(prefix: &str) -> String {
+  if prefix.is_empty() {
+    return EMPTY_PREFIX.to_string();
+  }
+  UNSAFE_REGEX.replace_all(prefix, "_").to_string()
+}
+
+struct LogFile {
+  path: PathBuf,
+  writer: LineWriter<File>,
+  size: u64,
+}
+
+impl LogFile {
+  fn create(path: PathBuf) -> io::Result<LogFile> {
+    let file = OpenOptions::new()
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&path)?;
+    Ok(LogFile {
+      path,
+      writer: LineWriter::new(file),
+      size: 0,
+    })
+  }
+
+  /// Moves `<prefix>.log` to `<prefix>.log.1`, `<prefix>.log.1` to `<prefix>.log.2` and so on,
+  /// dropping the oldest, and starts a new `<prefix>.log`
+  fn rotate(&mut self, keep: usize) -> io::Result<()> {
+    self.writer.flush()?;
+    let rotated = |i: usize| PathBuf::from(format!("{}.{}", self.path.display(), i));
+    if keep > 0 {
+      for i in (1..keep).rev() {
+        if rotated(i).exists() {
+          fs::rename(rotated(i), rotated(i + 1))?;
+        }
+      }
+      fs::rename(&self.path, rotated(1))?;
+    }
+    *self = LogFile::create(self.path.clone())?;
+    Ok(())
+  }
+}
+
+/// Writes the lines of each bucket to `<dir>/<prefix>.log`, rotating the files when they grow
+/// larger than `max_size`
+pub struct LogFiles {
+  dir: PathBuf,
+  max_size: Option<u64>,
+  /// Number of rotated files to keep per bucket
+  keep: usize,
+  files: HashMap<Log, LogFile>,
+}
+
+impl LogFiles {
+  pub fn new(dir: &Path, max_size: Option<u64>, keep: usize) -> Result<LogFiles> {
+    fs::create_dir_all(dir)
+      .with_context(|| format!("Failed to create log directory {}", dir.display()))?;
+    Ok(LogFiles {
+      dir: dir.to_path_buf(),
+      max_size,
+      keep,
+      files: HashMap::new(),
+    })
+  }
+
+  /// The file of a log. Buckets get a numeric suffix, like `api_2.log`, when their name is taken
+  /// by another bucket whose prefix sanitizes to the same name, or by the stderr or unparsed log
+  fn path_for(&self, log: &Log) -> PathBuf {
+    let path = |name: &str| self.dir.join(format!("{}.log", name));
+    let name = match log {
+      Log::Bucket(prefix) => sanitize_prefix(prefix),
+      special => return path(&special.to_string()),
+    };
+    let taken = |candidate: &String| {
+      candidate == STDERR_LOG
+        || candidate == UNPARSED_LOG
+        || self.files.values().any(|file| file.path == path(candidate))
+    };
+    let unique = iter::once(name.clone())
+      .chain((2..).map(|i| format!("{}_{}", name, i)))
+      .find(|candidate| !taken(candidate))
+      .unwrap();
+    path(&unique)
+  }
+
+  pub fn write(&mut self, log: &Log, line: &str) -> io::Result<()> {
+    if !self.files.contains_key(log) {
+      let file = LogFile::create(self.path_for(log))?;
+      self.files.insert(log.clone(), file);
+    }
+    let file = self.files.get_mut(log).unwrap();
+    if self
+      .max_size
+      .is_some_and(|max_size| file.size > 0 && file.size + line.len() as u64 >= max_size)
+    {
+      file.rotate(self.keep)?;
+    }
+    writeln!(file.writer, "{}", line)?;
+    file.size += line.len() as u64 + 1;
+    Ok(())
+  }
+
+  /// Writes a line to the file of every bucket written to so far
+  pub fn write_all(&mut self, line: &str) -> io::Result<()> {
+    let logs = self.files.keys().cloned().collect::<Vec<_>>();
+    for log in logs {
+      self.write(&log, line)?;
+    }
+    Ok(())
+  }
+}
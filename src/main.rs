@@ -1,114 +1,132 @@
+mod ansi;
 mod app;
 mod bucket;
 mod cli;
+mod config_file;
+mod keys;
+mod logs;
+mod presets;
+mod process;
 mod render;
+mod severity;
+mod structured;
+mod timeline;
+mod watch;
 
 use anyhow::Result;
-use app::AppMessage;
 use clap::CommandFactory;
-use clap::Parser;
 use cli::Config;
 use log::{debug, info};
-use mpsc::channel;
-use nix::{sys::signal, unistd::Pid};
-use signal::killpg;
 
 use std::io;
-use std::io::Read;
-use std::process::Child;
-use std::sync::mpsc::SendError;
-use std::thread;
-use std::{io::BufRead, io::BufReader, sync::mpsc};
-use std::{process::Stdio, sync::mpsc::Receiver};
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tui::{backend::CrosstermBackend, Terminal};
 
 use crate::{
   app::App,
+  process::Process,
   render::{setup_tui, teardown_tui},
 };
 
-fn spawn_reader_thread<S: Read + std::marker::Send + 'static>(stream: S) -> Receiver<String> {
-  let (tx, rx) = mpsc::channel::<String>();
-  thread::spawn(move || {
-    let reader = BufReader::new(stream);
-    reader
-      .lines()
-      .filter_map(|line| line.ok())
-      .for_each(|line| {
-        if let Err(e) = tx.send(line) {
-          debug!("Error sending line: {}", e);
-        }
-      });
-  });
-  rx
-}
-
-fn spawn_monitor_thread(mut child: Child) -> Receiver<AppMessage> {
-  let (tx, rx) = mpsc::channel::<AppMessage>();
-  thread::spawn(move || -> Result<(), SendError<_>> {
-    loop {
-      match child.try_wait() {
-        Ok(Some(code)) => {
-          tx.send(AppMessage::Exit(code))?;
-          break Ok(());
-        }
-        Ok(None) => (),
-        Err(_) => (),
-      }
-      thread::sleep(std::time::Duration::from_millis(16));
-    }
-  });
-  rx
-}
-
 fn main() -> Result<()> {
   env_logger::init();
-  let config = Config::parse();
-  let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout())).unwrap();
+  let config = match Config::load() {
+    Ok(config) => config,
+    Err(e) => Config::command()
+      .error(clap::error::ErrorKind::ValueValidation, format!("{:#}", e))
+      .exit(),
+  };
 
   let mut cmd = Config::command();
-  if config.command.len() == 0 {
+  let stdin = io::stdin();
+  if config.command.is_empty() && config.commands.is_empty() && stdin.is_terminal() {
     cmd
-      .error(clap::error::ErrorKind::InvalidValue, "No command provided")
+      .error(
+        clap::error::ErrorKind::InvalidValue,
+        "No command provided. Pass a command to run or pipe input into prism",
+      )
       .exit();
   }
+  let Some(named_commands) = config.named_commands() else {
+    cmd
+      .error(
+        clap::error::ErrorKind::InvalidValue,
+        "Named commands must be given as `name: command`",
+      )
+      .exit();
+  };
 
-  let shell_command = config.command.join(" ");
-  debug!("Running command: {}", shell_command);
-  debug!("Using regex: {}", config.prefix);
-  let args: Vec<String> = vec!["-c".to_string(), shell_command];
-  let mut process = std::process::Command::new("bash")
-    .args(&args)
-    .stderr(Stdio::piped())
-    .stdout(Stdio::piped())
-    .spawn()?;
-  let stdout = process.stdout.take().expect("Failed to open stdout");
-  let stderr = process.stderr.take().expect("Failed to open stderr");
-  let output = spawn_reader_thread(stdout);
-  let errors = spawn_reader_thread(stderr);
-  let monitor = spawn_monitor_thread(process);
+  let mut app = match App::new(&config) {
+    Ok(app) => app,
+    Err(e) => cmd
+      .error(clap::error::ErrorKind::ValueValidation, format!("{:#}", e))
+      .exit(),
+  };
 
-  setup_tui()?;
-  let mut app = App::new(&config);
-  let _res = app.run(&mut terminal, output, errors, monitor)?;
-  teardown_tui(&mut terminal)?;
+  debug!("Using regex: {}", config.prefix);
+  let mut processes = vec![];
+  if !config.command.is_empty() {
+    processes.push(Process::spawn(
+      None,
+      config.command.join(" "),
+      app.spawn_pty_size(),
+    )?);
+  } else if config.commands.is_empty() {
+    // When no command is given, we split whatever is piped into us. Keyboard input is still read
+    // from the terminal, since crossterm falls back to /dev/tty when stdin is not a tty.
+    processes.push(Process::stdin());
+  }
+  for (name, command) in named_commands {
+    processes.push(Process::spawn(Some(name), command, app.spawn_pty_size())?);
+  }
 
-  // NOTE: The below is my current attempt at ensuring that all child processes are killed when we exit.
-  // This does not seem to be reliable, however, but I can't figure out whether that's because I'm doing something wrong
-  // or whether because there is a bug in turborepo.
-  // I have previously had issues with dangling turborepo processes, which is why I think it is a possibility.
-  let (tx, rx) = channel::<()>();
+  // The commands are in their own process groups, so a ctrl-c in the terminal or a signal sent to
+  // prism doesn't reach them. Stop running instead and shut them down below
+  let interrupted = Arc::new(AtomicBool::new(false));
+  let handler_interrupted = interrupted.clone();
   ctrlc::set_handler(move || {
-    info!("Received SIGINT, sending SIGTERM to process group");
-    tx.send(()).expect("Failed to send signal");
+    info!("Received SIGINT or SIGTERM, shutting down");
+    handler_interrupted.store(true, Ordering::SeqCst);
   })
   .expect("Error setting Ctrl-C handler");
 
-  // This should send SIGINT to all our children and terminate them
-  killpg(Pid::this(), signal::Signal::SIGINT).expect("Failed to kill parent process group");
-  rx.recv().expect("Failed to receive signal");
-  // process.wait().expect("Failed to wait for process");
-  debug!("Process exited");
+  // Errors are returned only once the terminal is restored and the commands are shut down, so no
+  // processes are left running
+  let result = if config.no_tui {
+    app.run_headless(&mut processes, &interrupted)
+  } else {
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout())).unwrap();
+    let result = setup_tui().and_then(|_| app.run(&mut terminal, &mut processes, &interrupted));
+    let teardown = teardown_tui(&mut terminal);
+    result.and(teardown).map_err(Into::into)
+  };
 
+  // The commands may have been restarted, so the groups are collected once the app is done
+  let groups = processes
+    .iter()
+    .filter_map(|process| process.group)
+    .collect::<Vec<_>>();
+  for group in process::shutdown(&groups, &app.shutdown) {
+    eprintln!(
+      "prism: processes still running in process group {}: {}",
+      group,
+      process::group_members(group).join(", ")
+    );
+  }
+  // Commands still running when the app quit were stopped above because the user asked to, so
+  // only the commands that exited by themselves count
+  let statuses = processes
+    .iter()
+    .filter_map(|process| app.exit_status(process.name.as_ref()))
+    .collect::<Vec<_>>();
+  debug!("Processes exited: {:?}", statuses);
+  result?;
+
+  // Exit with the code of the first command that failed, so scripts running prism see the failure
+  if let Some(status) = statuses.into_iter().find(|status| !status.success()) {
+    std::process::exit(process::exit_code(status));
+  }
   Ok(())
 }
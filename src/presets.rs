@@ -0,0 +1,216 @@
+use regex::Regex;
+
+use std::time::Duration;
+
+use crate::cli::TURBO_REGEX;
+
+/// Name of the preset that picks one of the other presets based on the first lines of output
+pub const AUTO: &str = "auto";
+
+/// Number of lines looked at by the `auto` preset
+pub const AUTO_SAMPLE_SIZE: usize = 50;
+
+/// How long the `auto` preset waits for `AUTO_SAMPLE_SIZE` lines before picking a preset with the
+/// lines it has
+pub const AUTO_SAMPLE_TIME: Duration = Duration::from_secs(1);
+
+/// A built-in prefix regex for the output of a common tool
+#[derive(Debug)]
+pub struct Preset {
+  pub name: &'static str,
+  pub regex: &'static str,
+  /// Whether lines that don't match go in the bucket of the last line that did. Used for tools
+  /// that print a header before a block of output rather than a prefix on every line
+  pub sticky: bool,
+}
+
+/// The presets, with the more specific ones first since `auto` picks the first of equally good
+/// matches
+pub const PRESETS: &[Preset] = &[
+  // api-1  | listening on :8080
+  Preset {
+    name: "compose",
+    regex: r"^(?P<prefix>[\w.-]+?)(?:[-_]\d+)?\s+\| (?P<message>.*)",
+    sticky: false,
+  },
+  // 10:22:33 web.1  | started, or web    | started with overmind
+  Preset {
+    name: "foreman",
+    regex: r"^(?:(?P<timestamp>\d{2}:\d{2}:\d{2}) )?(?P<prefix>[\w-]+)(?:\.\d+)?\s+\| (?P<message>.*)",
+    sticky: false,
+  },
+  // [api] listening, or [0] listening. Also npm-run-all with --print-label, which pads the labels
+  // like [build:api ] done
+  Preset {
+    name: "concurrently",
+    regex: r"^\[(?P<prefix>[^\]]+?)\s*\] (?P<message>.*)",
+    sticky: false,
+  },
+  // packages/api dev: listening, or packages/api dev$ node index.js
+  Preset {
+    name: "pnpm",
+    regex: r"^(?P<prefix>\S+ [\w:.-]+)(?:\$|:) (?P<message>.*)",
+    sticky: false,
+  },
+  //    Compiling prism-rs v0.1.1 (/src/prism), followed by the warnings and errors for that crate.
+  // Crates built in parallel interleave their output, so it may follow the wrong crate
+  Preset {
+    name: "cargo",
+    regex: r"^\s+(?P<source>Compiling|Checking|Documenting|Fresh) (?P<prefix>[\w-]+) (?P<message>.*)",
+    sticky: true,
+  },
+  // make[1]: Entering directory '/src/lib', followed by the output for that directory
+  Preset {
+    name: "make",
+    regex: r"^make(?:\[\d+\])?: (?P<message>(?:Entering|Leaving) directory '(?P<prefix>[^']+)')",
+    sticky: true,
+  },
+  // @scope/api: listening, with --stream
+  Preset {
+    name: "lerna",
+    regex: r"^(?P<prefix>(?:@[\w.-]+/)?[\w.-]+): (?P<message>.*)",
+    sticky: false,
+  },
+  // api:build: done, or api: done with --output-style=stream
+  Preset {
+    name: "nx",
+    regex: r"^(?P<prefix>[\w@./-]+(?::[\w-]+)?): +(?P<message>.*)",
+    sticky: false,
+  },
+  // web:dev: ready
+  Preset {
+    name: "turbo",
+    regex: TURBO_REGEX,
+    sticky: false,
+  },
+];
+
+pub fn find(name: &str) -> Option<&'static Preset> {
+  PRESETS.iter().find(|p| p.name == name)
+}
+
+/// Names accepted by `--preset`
+pub fn names() -> Vec<&'static str> {
+  PRESETS.iter().map(|p| p.name).chain([AUTO]).collect()
+}
+
+/// Picks the preset that puts the most lines of the sample in a bucket. Returns `None` if no preset
+/// matches any of the lines
+pub fn detect(sample: &[String]) -> Option<&'static Preset> {
+  let mut best: Option<(&Preset, usize)> = None;
+  for preset in PRESETS {
+    let regex = Regex::new(preset.regex).unwrap();
+    let matches = |line: &String| {
+      regex
+        .captures(line)
+        .is_some_and(|caps| caps.name("prefix").is_some())
+    };
+    let score = if preset.sticky {
+      sample.len() - sample.iter().position(matches).unwrap_or(sample.len())
+    } else {
+      sample.iter().filter(|line| matches(line)).count()
+    };
+    if score > best.map_or(0, |(_, best)| best) {
+      best = Some((preset, score));
+    }
+  }
+  best.map(|(preset, _)| preset)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The prefix and message of a line parsed with a preset
+  fn parse(preset: &str, line: &str) -> Option<(String, String)> {
+    let regex = Regex::new(find(preset).unwrap().regex).unwrap();
+    let caps = regex.captures(line)?;
+    Some((caps["prefix"].to_string(), caps["message"].to_string()))
+  }
+
+  fn sample(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|line| line.to_string()).collect()
+  }
+
+  #[test]
+  fn presets_split_sample_lines() {
+    let cases = [
+      (
+        "compose",
+        "api-1  | listening on :8080",
+        "api",
+        "listening on :8080",
+      ),
+      ("foreman", "10:22:33 web.1  | started", "web", "started"),
+      ("concurrently", "[build:api ] done", "build:api", "done"),
+      (
+        "pnpm",
+        "packages/api dev: listening",
+        "packages/api dev",
+        "listening",
+      ),
+      (
+        "cargo",
+        "   Compiling prism-rs v0.1.1 (/src/prism)",
+        "prism-rs",
+        "v0.1.1 (/src/prism)",
+      ),
+      (
+        "make",
+        "make[1]: Entering directory '/src/lib'",
+        "/src/lib",
+        "Entering directory '/src/lib'",
+      ),
+      ("lerna", "@scope/api: listening", "@scope/api", "listening"),
+      ("nx", "api:build: done", "api:build", "done"),
+      ("turbo", "web:dev: ready", "web:dev", "ready"),
+    ];
+    for preset in PRESETS {
+      assert!(
+        cases.iter().any(|(name, ..)| *name == preset.name),
+        "no sample line for {}",
+        preset.name
+      );
+    }
+    for (preset, line, prefix, message) in cases {
+      assert_eq!(
+        parse(preset, line),
+        Some((prefix.to_string(), message.to_string())),
+        "{}",
+        preset
+      );
+    }
+  }
+
+  #[test]
+  fn detect_picks_the_preset_matching_most_lines() {
+    let compose = sample(&["api-1  | starting", "db-1   | ready", "api-1  | listening"]);
+    assert_eq!(detect(&compose).map(|p| p.name), Some("compose"));
+    let concurrently = sample(&["[api] starting", "[web] compiled", "[0] done"]);
+    assert_eq!(detect(&concurrently).map(|p| p.name), Some("concurrently"));
+  }
+
+  #[test]
+  fn detect_counts_the_lines_following_sticky_matches() {
+    let make = sample(&[
+      "make[1]: Entering directory '/src/lib'",
+      "cc -c lib.c",
+      "lib.c:3: warning: unused variable",
+      "make[1]: Leaving directory '/src/lib'",
+    ]);
+    assert_eq!(detect(&make).map(|p| p.name), Some("make"));
+    let cargo = sample(&[
+      "   Compiling prism-rs v0.1.1 (/src/prism)",
+      "warning: unused import: `std::fs`",
+      " --> src/main.rs:1:5",
+      "error: could not compile `prism-rs`",
+    ]);
+    assert_eq!(detect(&cargo).map(|p| p.name), Some("cargo"));
+  }
+
+  #[test]
+  fn detect_finds_nothing_without_prefixes() {
+    assert!(detect(&sample(&["just some output", "more output"])).is_none());
+    assert!(detect(&[]).is_none());
+  }
+}
@@ -0,0 +1,304 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc;
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{setsid, Pid};
+
+use std::fs::{self, File};
+use std::io;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{io::BufRead, io::BufReader};
+
+use crate::app::AppMessage;
+
+/// A source of lines for the app, either a spawned command or stdin
+pub struct Process {
+  /// Name given with `--command name: ...`. Output of named processes is bucketed under the name
+  pub name: Option<String>,
+  pub output: Receiver<String>,
+  pub errors: Receiver<String>,
+  pub monitor: Receiver<AppMessage>,
+  /// Process group of a spawned command, which holds the command and everything it starts
+  pub group: Option<Pid>,
+  /// The shell command, for restarting it
+  pub command: Option<String>,
+  /// The master side of the pseudo-terminal the command runs in, with `--pty`
+  pty: Option<File>,
+}
+
+nix::ioctl_write_ptr_bad!(set_window_size, libc::TIOCSWINSZ, Winsize);
+
+/// Takes ownership of a file descriptor, without leaking it into the commands spawned later
+fn close_on_exec(fd: RawFd) -> Result<File> {
+  fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+  Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Parses a pseudo-terminal size like `120x40`
+pub fn parse_pty_size(size: &str) -> Result<Winsize> {
+  let (columns, rows) = size
+    .split_once('x')
+    .and_then(|(columns, rows)| Some((columns.parse().ok()?, rows.parse().ok()?)))
+    .filter(|&(columns, rows)| columns > 0 && rows > 0)
+    .ok_or_else(|| anyhow!("Invalid size `{}`, expected COLUMNSxROWS", size))?;
+  Ok(Winsize {
+    ws_row: rows,
+    ws_col: columns,
+    ws_xpixel: 0,
+    ws_ypixel: 0,
+  })
+}
+
+impl Process {
+  /// Runs a shell command with its stdout and stderr piped, or in a pseudo-terminal of the given
+  /// size
+  pub fn spawn(
+    name: Option<String>,
+    shell_command: String,
+    pty: Option<Winsize>,
+  ) -> Result<Process> {
+    if let Some(size) = pty {
+      return Process::spawn_in_pty(name, shell_command, size);
+    }
+    debug!("Running command: {}", shell_command);
+    let args: Vec<String> = vec!["-c".to_string(), shell_command.clone()];
+    // The command gets its own process group so it and its children can be signalled together
+    let mut child = std::process::Command::new("bash")
+      .args(&args)
+      // A background process group reading the terminal would be stopped by SIGTTIN
+      .stdin(Stdio::null())
+      .stderr(Stdio::piped())
+      .stdout(Stdio::piped())
+      .process_group(0)
+      .spawn()?;
+    let group = Pid::from_raw(child.id() as i32);
+    let stdout = child.stdout.take().expect("Failed to open stdout");
+    let stderr = child.stderr.take().expect("Failed to open stderr");
+    Ok(Process {
+      name,
+      output: spawn_reader_thread(stdout),
+      errors: spawn_reader_thread(stderr),
+      monitor: spawn_monitor_thread(child),
+      group: Some(group),
+      command: Some(shell_command),
+      pty: None,
+    })
+  }
+
+  /// Runs a shell command in a pseudo-terminal, so it keeps its colors and line buffering. Its
+  /// stdout and stderr both go to the terminal, so they can't be told apart
+  fn spawn_in_pty(name: Option<String>, shell_command: String, size: Winsize) -> Result<Process> {
+    debug!("Running command in a pseudo-terminal: {}", shell_command);
+    let pty = openpty(&size, None).context("Failed to open a pseudo-terminal")?;
+    let master = close_on_exec(pty.master)?;
+    let terminal = close_on_exec(pty.slave)?;
+    let mut command = std::process::Command::new("bash");
+    command
+      .args(["-c", &shell_command])
+      .stdin(terminal.try_clone()?)
+      .stdout(terminal.try_clone()?)
+      .stderr(terminal);
+    // The command starts a new session with the terminal as its controlling terminal. The session
+    // is also a process group, so it and its children can be signalled together
+    unsafe {
+      command.pre_exec(|| {
+        setsid()?;
+        if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+          return Err(io::Error::last_os_error());
+        }
+        Ok(())
+      });
+    }
+    let child = command.spawn()?;
+    // The parent's copies of the terminal must be closed for reads to fail once the command exits
+    drop(command);
+    let group = Pid::from_raw(child.id() as i32);
+    Ok(Process {
+      name,
+      output: spawn_pty_reader_thread(master.try_clone()?),
+      errors: mpsc::channel::<String>().1,
+      monitor: spawn_monitor_thread(child),
+      group: Some(group),
+      command: Some(shell_command),
+      pty: Some(master),
+    })
+  }
+
+  /// Resizes the pseudo-terminal of a command run with `--pty`
+  pub fn resize(&self, size: Winsize) {
+    if let Some(master) = &self.pty {
+      if let Err(e) = unsafe { set_window_size(master.as_raw_fd(), &size) } {
+        debug!("Error resizing pseudo-terminal: {}", e);
+      }
+    }
+  }
+
+  pub fn stdin() -> Process {
+    debug!("Reading from stdin");
+    Process {
+      name: None,
+      output: spawn_reader_thread(io::stdin()),
+      errors: mpsc::channel::<String>().1,
+      monitor: mpsc::channel::<AppMessage>().1,
+      group: None,
+      command: None,
+      pty: None,
+    }
+  }
+}
+
+pub fn spawn_reader_thread<S: Read + std::marker::Send + 'static>(stream: S) -> Receiver<String> {
+  let (tx, rx) = mpsc::channel::<String>();
+  thread::spawn(move || {
+    let reader = BufReader::new(stream);
+    reader.lines().map_while(Result::ok).for_each(|line| {
+      if let Err(e) = tx.send(line) {
+        debug!("Error sending line: {}", e);
+      }
+    });
+  });
+  rx
+}
+
+/// Reads lines from the master side of a pseudo-terminal. Reads fail once the command and
+/// everything it started have closed the terminal
+fn spawn_pty_reader_thread(master: File) -> Receiver<String> {
+  let (tx, rx) = mpsc::channel::<String>();
+  thread::spawn(move || {
+    let reader = BufReader::new(master);
+    reader.lines().map_while(Result::ok).for_each(|line| {
+      // The terminal ends lines with `\r\n`, and progress output redraws a line after a `\r`, so
+      // only the last version of the line is kept
+      let line = line.trim_end_matches('\r');
+      let line = line.rsplit('\r').next().unwrap_or(line);
+      if let Err(e) = tx.send(line.to_string()) {
+        debug!("Error sending line: {}", e);
+      }
+    });
+  });
+  rx
+}
+
+pub fn spawn_monitor_thread(mut child: Child) -> Receiver<AppMessage> {
+  let (tx, rx) = mpsc::channel::<AppMessage>();
+  thread::spawn(move || -> Result<(), SendError<_>> {
+    loop {
+      match child.try_wait() {
+        Ok(Some(code)) => {
+          tx.send(AppMessage::Exit(code))?;
+          break Ok(());
+        }
+        Ok(None) => (),
+        Err(_) => (),
+      }
+      thread::sleep(std::time::Duration::from_millis(16));
+    }
+  });
+  rx
+}
+
+/// The exit code of a process, or 128 plus the signal for a process that was killed by one, like
+/// a shell reports it
+pub fn exit_code(status: ExitStatus) -> i32 {
+  status
+    .code()
+    .or_else(|| status.signal().map(|signal| 128 + signal))
+    .unwrap_or(1)
+}
+
+/// A signal sent to the process groups on shutdown, and how long to wait for them to exit
+#[derive(Clone, Copy, Debug)]
+pub struct ShutdownStep {
+  pub signal: Signal,
+  pub timeout: Duration,
+}
+
+/// Parses a shutdown sequence like `SIGINT:3,SIGTERM:3,SIGKILL`. The timeouts are in seconds, and
+/// default to 1
+pub fn parse_shutdown_sequence(sequence: &str) -> Result<Vec<ShutdownStep>> {
+  sequence
+    .split(',')
+    .map(|step| {
+      let (signal, timeout) = step.trim().split_once(':').unwrap_or((step.trim(), "1"));
+      let name = signal.to_uppercase();
+      let name = if name.starts_with("SIG") {
+        name
+      } else {
+        format!("SIG{}", name)
+      };
+      let signal = Signal::from_str(&name).map_err(|_| anyhow!("Unknown signal `{}`", signal))?;
+      let timeout = timeout
+        .parse::<f64>()
+        .ok()
+        .filter(|t| *t >= 0.0)
+        .with_context(|| format!("Invalid timeout `{}` for {}", timeout, signal))?;
+      Ok(ShutdownStep {
+        signal,
+        timeout: Duration::from_secs_f64(timeout),
+      })
+    })
+    .collect()
+}
+
+fn is_alive(group: Pid) -> bool {
+  killpg(group, None).is_ok()
+}
+
+/// Sends each signal of the sequence to the process groups that are still alive, waiting up to its
+/// timeout for them to exit. The commands themselves are reaped by their monitor threads. Returns
+/// the groups that are still alive at the end
+pub fn shutdown(groups: &[Pid], sequence: &[ShutdownStep]) -> Vec<Pid> {
+  let mut alive = groups
+    .iter()
+    .copied()
+    .filter(|&group| is_alive(group))
+    .collect::<Vec<_>>();
+  for step in sequence {
+    if alive.is_empty() {
+      break;
+    }
+    for &group in &alive {
+      info!("Sending {} to process group {}", step.signal, group);
+      if let Err(e) = killpg(group, step.signal) {
+        debug!("Error signalling process group {}: {}", group, e);
+      }
+    }
+    let deadline = Instant::now() + step.timeout;
+    loop {
+      alive.retain(|&group| is_alive(group));
+      if alive.is_empty() || Instant::now() >= deadline {
+        break;
+      }
+      thread::sleep(Duration::from_millis(16));
+    }
+  }
+  alive
+}
+
+/// The processes in a process group as `pid (name)`, read from `/proc`
+pub fn group_members(group: Pid) -> Vec<String> {
+  let Ok(entries) = fs::read_dir("/proc") else {
+    return vec![];
+  };
+  entries
+    .flatten()
+    .filter_map(|entry| {
+      let stat = fs::read_to_string(entry.path().join("stat")).ok()?;
+      // The name is in parentheses and may contain spaces, so the fields after it are split from
+      // the last parenthesis: state, parent pid, process group, ...
+      let (head, rest) = stat.rsplit_once(')')?;
+      let (pid, name) = head.split_once(" (")?;
+      let pgrp = rest.split_whitespace().nth(2)?;
+      (pgrp == group.to_string()).then(|| format!("{} ({})", pid, name))
+    })
+    .collect()
+}
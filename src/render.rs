@@ -1,32 +1,134 @@
 use ansi_to_tui::IntoText;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use crossterm::{
   event::{DisableMouseCapture, EnableMouseCapture},
   execute,
   terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 
+use regex::Regex;
+use std::collections::HashMap;
 use std::io;
-use std::io::{Read, Stdout};
+use std::io::Stdout;
 use tui::{
   backend::CrosstermBackend,
   layout::{Constraint, Direction, Layout, Rect},
   style::{Color, Modifier, Style},
-  text::{Span, Spans},
-  widgets::{Block, Borders, List, ListItem},
+  text::{Span, Spans, Text},
+  widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
   Frame, Terminal,
 };
 
-use crate::app::App;
+use crate::ansi::Stripped;
+use crate::app::{App, Line};
+use crate::severity::Severity;
+use crate::structured;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DisplayState {
   Messages,
   Errors,
   ParseErrors,
+  /// All buckets merged in the order their lines arrived
+  Timeline,
+  /// The whole structured object of a line
+  Detail,
   // Help,
 }
 
+/// Colors used by the TUI, configurable in the `[colors]` section of the config file
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+  pub title: Color,
+  pub text: Color,
+  pub selected: Color,
+  pub error: Color,
+  pub warning: Color,
+  pub new_messages: Color,
+  pub unparsed: Color,
+  pub search: Color,
+  pub filter: Color,
+}
+
+impl Default for Theme {
+  fn default() -> Self {
+    Theme {
+      title: Color::Green,
+      text: Color::White,
+      selected: Color::Blue,
+      error: Color::Red,
+      warning: Color::Yellow,
+      new_messages: Color::Cyan,
+      unparsed: Color::Yellow,
+      search: Color::Yellow,
+      filter: Color::Magenta,
+    }
+  }
+}
+
+impl Theme {
+  /// Creates the default theme with the given colors replaced
+  pub fn new(colors: &HashMap<String, String>) -> Result<Theme> {
+    let mut theme = Theme::default();
+    for (name, color) in colors {
+      let color = parse_color(color).ok_or_else(|| anyhow!("Unknown color `{}`", color))?;
+      let field = match name.as_str() {
+        "title" => &mut theme.title,
+        "text" => &mut theme.text,
+        "selected" => &mut theme.selected,
+        "error" => &mut theme.error,
+        "warning" => &mut theme.warning,
+        "new-messages" => &mut theme.new_messages,
+        "unparsed" => &mut theme.unparsed,
+        "search" => &mut theme.search,
+        "filter" => &mut theme.filter,
+        _ => bail!("Unknown color name `{}` in [colors]", name),
+      };
+      *field = color;
+    }
+    Ok(theme)
+  }
+}
+
+/// Parses a color name like `red` or `light-blue`, a hex color like `#ff8800` or a 256 color index
+fn parse_color(color: &str) -> Option<Color> {
+  if let Some(hex) = color.strip_prefix('#') {
+    let value = u32::from_str_radix(hex, 16)
+      .ok()
+      .filter(|_| hex.len() == 6)?;
+    return Some(Color::Rgb(
+      (value >> 16) as u8,
+      (value >> 8) as u8,
+      value as u8,
+    ));
+  }
+  if let Ok(index) = color.parse::<u8>() {
+    return Some(Color::Indexed(index));
+  }
+  Some(
+    match color.to_lowercase().replace(['-', '_'], "").as_str() {
+      "reset" => Color::Reset,
+      "black" => Color::Black,
+      "red" => Color::Red,
+      "green" => Color::Green,
+      "yellow" => Color::Yellow,
+      "blue" => Color::Blue,
+      "magenta" => Color::Magenta,
+      "cyan" => Color::Cyan,
+      "gray" | "grey" => Color::Gray,
+      "darkgray" | "darkgrey" => Color::DarkGray,
+      "lightred" => Color::LightRed,
+      "lightgreen" => Color::LightGreen,
+      "lightyellow" => Color::LightYellow,
+      "lightblue" => Color::LightBlue,
+      "lightmagenta" => Color::LightMagenta,
+      "lightcyan" => Color::LightCyan,
+      "white" => Color::White,
+      _ => return None,
+    },
+  )
+}
+
 pub fn draw(app: &mut App, f: &mut Frame<CrosstermBackend<io::Stdout>>) {
   let size = f.size();
   let main_chunks = Layout::default()
@@ -39,54 +141,130 @@ pub fn draw(app: &mut App, f: &mut Frame<CrosstermBackend<io::Stdout>>) {
     .constraints([Constraint::Min(0), Constraint::Length(4)].as_ref())
     .split(main_chunks[1]);
 
-  render_messages(app, f, main_chunks[0]);
+  if let Some(prompt) = app.prompt.clone() {
+    let message_chunks = Layout::default()
+      .direction(Direction::Vertical)
+      .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+      .split(main_chunks[0]);
+    render_messages(app, f, message_chunks[0]);
+    f.render_widget(
+      Paragraph::new(format!("{}{}", prompt.label(), prompt.text)),
+      message_chunks[1],
+    );
+  } else {
+    render_messages(app, f, main_chunks[0]);
+  }
   render_prefix_list(app, f, right_chunks[0]);
   render_other_list(app, f, right_chunks[1]);
-
-  ()
 }
 
 fn render_messages(app: &mut App, f: &mut Frame<CrosstermBackend<io::Stdout>>, destination: Rect) {
-  let height = f.size().height.into();
-  let messages = app
-    .get_current_messages(height)
+  let theme = app.theme;
+  let rows = usize::from(destination.height).saturating_sub(2);
+  let current_match = app.get_current_bucket().and_then(|b| b.current_match);
+  let current = app.get_current_messages(rows);
+  let messages = current
     .iter()
-    .map(|s| ListItem::new(s.clone().into_bytes().into_text().unwrap()))
+    .map(|(i, l)| {
+      let mut text = ansi_text(&l.display());
+      if let Some(search) = &app.search {
+        let style = if current_match == Some(*i) {
+          Style::default().fg(Color::Black).bg(theme.search)
+        } else {
+          Style::default().add_modifier(Modifier::REVERSED)
+        };
+        text = highlight_matches(text, search, style);
+      }
+      let item = ListItem::new(text);
+      match &app.current_error {
+        Some((prefix, seq)) if l.prefix.as_ref() == Some(prefix) && l.seq == *seq => {
+          item.style(Style::default().bg(theme.error))
+        }
+        _ => item,
+      }
+    })
     .collect::<Vec<ListItem>>();
 
+  app.message_area = destination;
+  app.shown_records = if app.display_state == DisplayState::Messages {
+    shown_records(current.iter().map(|(_, l)| l))
+  } else {
+    vec![]
+  };
+
   match app.display_state {
     DisplayState::Messages => {
       let prefix = app.get_selected_prefix();
       let mut pieces: Vec<Span> = vec![];
+      let command_exit = prefix
+        .as_ref()
+        .and_then(|p| app.get_exit_code_for(p))
+        .map(|(name, code)| format!(" ({} exited: {}) ", name, code));
       if let Some(p) = prefix {
         pieces.push(Span::styled(
           format!(" Messages for {} ", p),
-          Style::default().fg(Color::Green),
+          Style::default().fg(theme.title),
         ));
-        if app
-          .get_current_bucket()
-          .map_or(false, |b| b.scroll.is_some())
-        {
+        if app.get_current_bucket().is_some_and(|b| b.scroll.is_some()) {
           pieces.push(Span::styled(
             format!(
               "({} older) ",
-              app.get_current_bucket().unwrap().get_older(height)
+              app.get_current_bucket().unwrap().get_older(rows)
             ),
-            Style::default().fg(Color::Green),
+            Style::default().fg(theme.title),
           ));
         }
       } else {
         pieces.push(Span::styled(
           " Messages ".to_string(),
-          Style::default().fg(Color::Green),
+          Style::default().fg(theme.title),
         ));
       };
+      if let Some(bucket) = app.get_current_bucket() {
+        if let Some(filter) = bucket.get_filter() {
+          pieces.push(Span::styled(
+            format!(
+              " filter: {} ({} of {} lines) ",
+              filter.text,
+              bucket.view_len(),
+              bucket.get_all_messages().len()
+            ),
+            Style::default().fg(theme.filter),
+          ));
+        }
+      }
+      if let Some(min) = app.get_current_bucket().and_then(|b| b.get_min_severity()) {
+        pieces.push(Span::styled(
+          format!(" {}+ ", min.name()),
+          Style::default().fg(theme.filter),
+        ));
+      }
+      match app.get_match_position() {
+        Some((Some(position), total)) => pieces.push(Span::styled(
+          format!(" match {} of {} ", position, total),
+          Style::default().fg(theme.search),
+        )),
+        Some((None, total)) => pieces.push(Span::styled(
+          format!(" {} matches ", total),
+          Style::default().fg(theme.search),
+        )),
+        None => {}
+      }
+      if app.is_restarting() {
+        pieces.push(Span::styled(
+          " restarting… ",
+          Style::default().fg(theme.title),
+        ));
+      }
       if let Some(b) = app.exit_code {
         pieces.push(Span::styled(
           format!(" (process exited: {}) ", b),
-          Style::default().fg(Color::Red),
+          Style::default().fg(theme.error),
         ));
       }
+      if let Some(exit) = command_exit {
+        pieces.push(Span::styled(exit, Style::default().fg(theme.error)));
+      }
       // let title = pieces.join(" ");
       let list = List::new(messages)
         .block(
@@ -101,26 +279,91 @@ fn render_messages(app: &mut App, f: &mut Frame<CrosstermBackend<io::Stdout>>, d
     DisplayState::Errors => {
       let errors: Vec<ListItem> = app
         .error_messages
-        .get_messages(height - 2)
+        .get_messages(rows)
         .iter()
-        .map(|s| ListItem::new(s.message.to_string()))
+        .map(|s| ListItem::new(s.render()))
         .collect::<Vec<ListItem>>();
 
       let error_list = List::new(errors).block(
         Block::default()
           .borders(Borders::ALL)
           .title("Errors")
-          .style(Style::default().fg(Color::Red)),
+          .style(Style::default().fg(theme.error)),
       );
       f.render_widget(error_list, destination);
     }
+    DisplayState::Timeline => {
+      let timeline = app.get_timeline_messages(rows);
+      app.shown_records = shown_records(timeline.iter());
+      let lines = timeline
+        .iter()
+        .map(|line| {
+          let prefix = line.prefix.clone().unwrap_or_default();
+          let mut text = ansi_text(&line.display());
+          if let Some(search) = &app.search {
+            text = highlight_matches(
+              text,
+              search,
+              Style::default().add_modifier(Modifier::REVERSED),
+            );
+          }
+          if let Some(first) = text.lines.first_mut() {
+            first.0.insert(
+              0,
+              Span::styled(
+                format!("{}: ", prefix),
+                Style::default().fg(prefix_color(&prefix)),
+              ),
+            );
+          }
+          ListItem::new(text)
+        })
+        .collect::<Vec<ListItem>>();
+      let title = if app.marked.is_empty() {
+        " All buckets ".to_string()
+      } else {
+        let mut marked = app.marked.iter().cloned().collect::<Vec<_>>();
+        marked.sort();
+        format!(" Merged {} ", marked.join(", "))
+      };
+      let mut pieces = vec![Span::styled(title, Style::default().fg(theme.title))];
+      if app.timeline.scroll.is_some() {
+        pieces.push(Span::styled(
+          format!("({} older) ", app.get_timeline_older()),
+          Style::default().fg(theme.title),
+        ));
+      }
+      let list = List::new(lines).block(
+        Block::default()
+          .borders(Borders::ALL)
+          .title(Spans::from(pieces)),
+      );
+      f.render_widget(list, destination);
+    }
+    DisplayState::Detail => {
+      let line = app.get_focused_line();
+      let text = line
+        .as_ref()
+        .map(|l| structured::pretty(l.raw.as_deref().unwrap_or(&l.message)))
+        .unwrap_or_default();
+      let title = match line.and_then(|l| l.prefix) {
+        Some(prefix) => format!(" Detail for {} ", prefix),
+        None => " Detail ".to_string(),
+      };
+      let paragraph = Paragraph::new(text).wrap(Wrap { trim: false }).block(
+        Block::default()
+          .borders(Borders::ALL)
+          .title(Span::styled(title, Style::default().fg(theme.title))),
+      );
+      f.render_widget(paragraph, destination);
+    }
     DisplayState::ParseErrors => {
       let list = List::new(
         app
           .unprefixed_messages
-          .get_messages(height - 2)
+          .get_messages(rows)
           .iter()
-          .map(|s| ListItem::new(s.message.clone().into_bytes().into_text().unwrap()))
+          .map(|s| ListItem::new(ansi_text(&s.message)))
           .collect::<Vec<ListItem>>(),
       )
       .block(Block::default().borders(Borders::ALL).title(" no parse "));
@@ -129,33 +372,119 @@ fn render_messages(app: &mut App, f: &mut Frame<CrosstermBackend<io::Stdout>>, d
   }
 }
 
+/// The prefix, sequence number and height of each shown record
+fn shown_records<'a>(lines: impl Iterator<Item = &'a Line>) -> Vec<(String, u64, usize)> {
+  lines
+    .map(|l| (l.prefix.clone().unwrap_or_default(), l.seq, l.height()))
+    .collect()
+}
+
+/// The line with its escape sequences turned into styles, or left out if they can't be parsed
+fn ansi_text(line: &str) -> Text<'static> {
+  line
+    .into_text()
+    .unwrap_or_else(|_| Text::raw(Stripped::new(line).text))
+}
+
+/// Highlights the parts of the text matching the search. Matches that span text with different
+/// styles are not highlighted.
+fn highlight_matches(text: Text<'static>, search: &Regex, style: Style) -> Text<'static> {
+  let lines = text
+    .lines
+    .into_iter()
+    .map(|spans| {
+      Spans(
+        spans
+          .0
+          .into_iter()
+          .flat_map(|span| {
+            let mut pieces = vec![];
+            let mut last = 0;
+            for m in search.find_iter(&span.content) {
+              if m.start() > last {
+                pieces.push(Span::styled(
+                  span.content[last..m.start()].to_string(),
+                  span.style,
+                ));
+              }
+              pieces.push(Span::styled(
+                m.as_str().to_string(),
+                span.style.patch(style),
+              ));
+              last = m.end();
+            }
+            if last < span.content.len() {
+              pieces.push(Span::styled(span.content[last..].to_string(), span.style));
+            }
+            pieces
+          })
+          .collect(),
+      )
+    })
+    .collect::<Vec<_>>();
+  Text::from(lines)
+}
+
+/// Picks a color for a prefix, so the same prefix always gets the same color
+fn prefix_color(prefix: &str) -> Color {
+  const COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Yellow,
+    Color::Blue,
+    Color::Green,
+    Color::LightRed,
+  ];
+  let hash = prefix.bytes().fold(0usize, |hash, b| {
+    hash.wrapping_mul(31).wrapping_add(b.into())
+  });
+  COLORS[hash % COLORS.len()]
+}
+
 fn render_prefix_list(
   app: &mut App,
   f: &mut Frame<CrosstermBackend<io::Stdout>>,
   destination: Rect,
 ) {
+  let theme = app.theme;
   let titles: Vec<Spans> = app
     .get_buckets()
     .iter()
     .map(|(label, bucket)| {
       Spans(vec![
         Span::styled(
-          format!("{:3} ", bucket.new_errors),
-          Style::default().fg(if bucket.new_messages > 0 {
-            Color::Red
+          if app.marked.contains(*label) {
+            "*"
           } else {
-            Color::White
+            " "
+          },
+          Style::default().fg(theme.unparsed),
+        ),
+        Span::styled(
+          format!("{:3} ", bucket.new_at_least(Severity::Error)),
+          Style::default().fg(if bucket.new_at_least(Severity::Error) > 0 {
+            theme.error
+          } else {
+            theme.text
+          }),
+        ),
+        Span::styled(
+          format!("{:3} ", bucket.new_count(Severity::Warn)),
+          Style::default().fg(if bucket.new_count(Severity::Warn) > 0 {
+            theme.warning
+          } else {
+            theme.text
           }),
         ),
         Span::styled(
           format!("{:3} ", bucket.new_messages),
           Style::default().fg(if bucket.new_messages > 0 {
-            Color::Cyan
+            theme.new_messages
           } else {
-            Color::White
+            theme.text
           }),
         ),
-        Span::styled(label.clone(), Style::default().fg(Color::White)),
+        Span::styled((*label).clone(), Style::default().fg(theme.text)),
       ])
     })
     .collect();
@@ -169,48 +498,51 @@ fn render_prefix_list(
   .highlight_style(
     Style::default()
       .add_modifier(Modifier::BOLD)
-      .bg(Color::Blue),
+      .bg(theme.selected),
   )
   .block(
     Block::default()
       .borders(Borders::ALL)
       .title("Prefixes")
-      .style(Style::default().fg(Color::White)),
+      .style(Style::default().fg(theme.text)),
   );
 
   f.render_stateful_widget(tabs, destination, &mut app.list_state.clone());
 }
 
 fn render_other_list(app: &mut App, f: &mut Frame<CrosstermBackend<io::Stdout>>, target: Rect) {
+  let theme = app.theme;
   // debug!("target: {:?}", target);
   let error_style = if app.error_messages.new_messages > 0 {
-    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+    Style::default()
+      .fg(theme.error)
+      .add_modifier(Modifier::BOLD)
   } else if app.display_state == DisplayState::Errors {
-    Style::default().fg(Color::Red)
+    Style::default().fg(theme.error)
   } else {
-    Style::default().fg(Color::White)
+    Style::default().fg(theme.text)
   };
   let unprefixed_style = if app.unprefixed_messages.new_messages > 0 {
     Style::default()
-      .fg(Color::Yellow)
+      .fg(theme.unparsed)
       .add_modifier(Modifier::BOLD)
   } else if app.display_state == DisplayState::ParseErrors {
-    Style::default().fg(Color::Yellow)
+    Style::default().fg(theme.unparsed)
   } else {
-    Style::default().fg(Color::White)
+    Style::default().fg(theme.text)
   };
   let list = List::new(vec![
     ListItem::new(Spans(vec![
       Span::styled(
         format!("{:3} ", app.error_messages.new_messages),
-        Style::default().fg(Color::Yellow),
+        Style::default().fg(theme.unparsed),
       ),
       Span::styled("stderr", error_style),
     ])),
     ListItem::new(Spans(vec![
       Span::styled(
         format!("{:3} ", app.unprefixed_messages.new_messages),
-        Style::default().fg(Color::Yellow),
+        Style::default().fg(theme.unparsed),
       ),
       Span::styled("no parse", unprefixed_style),
     ])),
@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+
+use std::collections::HashMap;
+
+/// How severe a line is, from least to most severe
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+  Trace,
+  Debug,
+  Info,
+  Warn,
+  Error,
+  Fatal,
+}
+
+impl Severity {
+  pub const ALL: [Severity; 6] = [
+    Severity::Trace,
+    Severity::Debug,
+    Severity::Info,
+    Severity::Warn,
+    Severity::Error,
+    Severity::Fatal,
+  ];
+
+  /// Name used in the `[severity]` section of the config file and for named capture groups
+  pub fn name(self) -> &'static str {
+    match self {
+      Severity::Trace => "trace",
+      Severity::Debug => "debug",
+      Severity::Info => "info",
+      Severity::Warn => "warn",
+      Severity::Error => "error",
+      Severity::Fatal => "fatal",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Severity> {
+    Severity::ALL.into_iter().find(|s| s.name() == name)
+  }
+
+  /// Parses a level as written by common loggers, like `WARN`, `warning`, `E` or `crit`, or as the
+  /// numbers pino and bunyan use, from 10 for trace to 60 for fatal
+  pub fn parse(level: &str) -> Option<Severity> {
+    Some(match level.trim().to_lowercase().as_str() {
+      "trace" | "trc" | "t" | "10" => Severity::Trace,
+      "debug" | "dbg" | "verbose" | "d" | "20" => Severity::Debug,
+      "info" | "inf" | "information" | "notice" | "i" | "30" => Severity::Info,
+      "warn" | "warning" | "wrn" | "w" | "40" => Severity::Warn,
+      "error" | "err" | "e" | "50" => Severity::Error,
+      "fatal" | "critical" | "crit" | "panic" | "emerg" | "alert" | "f" | "60" => Severity::Fatal,
+      _ => return None,
+    })
+  }
+
+  /// The next minimum severity shown when cycling through all, info and up, warn and up and error
+  /// and up
+  pub fn next_minimum(minimum: Option<Severity>) -> Option<Severity> {
+    match minimum {
+      None => Some(Severity::Info),
+      Some(Severity::Trace | Severity::Debug | Severity::Info) => Some(Severity::Warn),
+      Some(Severity::Warn) => Some(Severity::Error),
+      Some(Severity::Error | Severity::Fatal) => None,
+    }
+  }
+
+  /// ANSI color code for the level of a line
+  pub fn ansi_color(self) -> u8 {
+    match self {
+      Severity::Trace | Severity::Debug => 2,
+      Severity::Info => 32,
+      Severity::Warn => 33,
+      Severity::Error | Severity::Fatal => 31,
+    }
+  }
+}
+
+/// Default rules for lines without a recognised level. Plurals are left out so lines like
+/// `0 errors` don't count
+const DEFAULT_RULES: &[(Severity, &str)] = &[
+  (Severity::Fatal, r"(?i)\b(fatal|panicked)\b"),
+  (
+    Severity::Error,
+    r"(?i)\b(error|exception|stack ?trace|traceback)\b",
+  ),
+  (Severity::Warn, r"(?i)\b(warn|warning)\b"),
+];
+
+/// Level markers written by common loggers, like `INFO` in `12:00:01 INFO starting`
+const MARKER_REGEX: &str = r"\b(TRACE|DEBUG|INFO|WARN|WARNING|ERROR|FATAL|CRITICAL)\b";
+
+/// Decides the severity of lines, from their level or from a regex per severity
+#[derive(Clone, Debug)]
+pub struct SeverityRules {
+  markers: Regex,
+  /// Most severe first, so the most severe matching rule wins
+  rules: Vec<(Severity, Regex)>,
+}
+
+impl SeverityRules {
+  /// Creates the default rules with the regexes of the severities in `overrides` replaced. An
+  /// empty regex removes the rule
+  pub fn new(overrides: &HashMap<String, String>) -> Result<SeverityRules> {
+    let mut patterns = DEFAULT_RULES
+      .iter()
+      .map(|(severity, pattern)| (*severity, pattern.to_string()))
+      .collect::<HashMap<_, _>>();
+    for (name, pattern) in overrides {
+      let severity = Severity::from_name(name).ok_or_else(|| {
+        anyhow!(
+          "Unknown severity `{}`, expected one of trace, debug, info, warn, error or fatal",
+          name
+        )
+      })?;
+      patterns.insert(severity, pattern.clone());
+    }
+    let mut rules = patterns
+      .into_iter()
+      .filter(|(_, pattern)| !pattern.is_empty())
+      .map(|(severity, pattern)| {
+        Regex::new(&pattern)
+          .with_context(|| format!("Invalid {} regex `{}`", severity.name(), pattern))
+          .map(|regex| (severity, regex))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    rules.sort_by_key(|(severity, _)| std::cmp::Reverse(*severity));
+    Ok(SeverityRules {
+      markers: Regex::new(MARKER_REGEX).unwrap(),
+      rules,
+    })
+  }
+
+  /// The severity of a line: its level if it is a known one, otherwise the first level marker in
+  /// the text, otherwise the most severe rule matching the text
+  pub fn severity(&self, level: Option<&str>, text: &str) -> Option<Severity> {
+    level
+      .and_then(Severity::parse)
+      .or_else(|| {
+        self
+          .markers
+          .find(text)
+          .and_then(|m| Severity::parse(m.as_str()))
+      })
+      .or_else(|| {
+        self
+          .rules
+          .iter()
+          .find(|(_, regex)| regex.is_match(text))
+          .map(|(severity, _)| *severity)
+      })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rules() -> SeverityRules {
+    SeverityRules::new(&HashMap::new()).unwrap()
+  }
+
+  #[test]
+  fn parses_level_names_and_abbreviations() {
+    assert_eq!(Severity::parse("WARNING"), Some(Severity::Warn));
+    assert_eq!(Severity::parse(" E "), Some(Severity::Error));
+    assert_eq!(Severity::parse("crit"), Some(Severity::Fatal));
+    assert_eq!(Severity::parse("verbose"), Some(Severity::Debug));
+    assert_eq!(Severity::parse("loud"), None);
+  }
+
+  #[test]
+  fn parses_pino_level_numbers() {
+    let levels = ["10", "20", "30", "40", "50", "60"].map(Severity::parse);
+    assert_eq!(levels, Severity::ALL.map(Some));
+    assert_eq!(Severity::parse("35"), None);
+  }
+
+  #[test]
+  fn level_wins_over_the_text() {
+    assert_eq!(
+      rules().severity(Some("30"), "error: retrying"),
+      Some(Severity::Info)
+    );
+    assert_eq!(
+      rules().severity(Some("custom"), "error: retrying"),
+      Some(Severity::Error)
+    );
+  }
+
+  #[test]
+  fn markers_win_over_the_rules() {
+    assert_eq!(
+      rules().severity(None, "12:00:01 WARN error budget low"),
+      Some(Severity::Warn)
+    );
+  }
+
+  #[test]
+  fn rules_skip_plurals() {
+    assert_eq!(rules().severity(None, "finished with 0 errors"), None);
+    assert_eq!(rules().severity(None, "2 warnings"), None);
+    assert_eq!(
+      rules().severity(None, "thread 'main' panicked at src/main.rs"),
+      Some(Severity::Fatal)
+    );
+  }
+
+  #[test]
+  fn overrides_replace_or_remove_rules() {
+    let overrides = HashMap::from([
+      ("warn".to_string(), "(?i)deprecated".to_string()),
+      ("error".to_string(), String::new()),
+    ]);
+    let rules = SeverityRules::new(&overrides).unwrap();
+    assert_eq!(rules.severity(None, "Deprecated API"), Some(Severity::Warn));
+    assert_eq!(rules.severity(None, "warning: slow"), None);
+    assert_eq!(rules.severity(None, "error: failed"), None);
+    assert!(SeverityRules::new(&HashMap::from([("loud".to_string(), "x".to_string())])).is_err());
+  }
+}
@@ -0,0 +1,227 @@
+use serde_json::Value;
+
+use crate::app::Line;
+
+/// Names of the fields that structured lines are split on. Nested JSON fields are separated by
+/// dots, e.g. `kubernetes.pod`
+#[derive(Clone, Debug)]
+pub struct FieldNames {
+  pub prefix: String,
+  pub message: String,
+  pub level: String,
+  pub timestamp: String,
+}
+
+/// Looks up a dotted path in a JSON object
+fn json_field<'a>(object: &'a Value, path: &str) -> Option<&'a Value> {
+  path
+    .split('.')
+    .try_fold(object, |value, key| value.as_object()?.get(key))
+}
+
+fn json_string(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.clone(),
+    other => other.to_string(),
+  }
+}
+
+/// Pretty prints the raw JSON or logfmt of a line, or returns it as is if it is neither
+pub fn pretty(raw: &str) -> String {
+  if let Ok(pretty) =
+    serde_json::from_str::<Value>(raw).and_then(|value| serde_json::to_string_pretty(&value))
+  {
+    return pretty;
+  }
+  match logfmt_pairs(raw) {
+    Some(pairs) => pairs
+      .iter()
+      .map(|(key, value)| format!("{} = {}", key, value))
+      .collect::<Vec<_>>()
+      .join("\n"),
+    None => raw.to_string(),
+  }
+}
+
+/// Parses a line holding a JSON object. Returns `None` if the line isn't one. The line is kept as
+/// `raw` so the whole object can be shown
+pub fn parse_json(line: &str, fields: &FieldNames) -> Option<Line> {
+  let object = serde_json::from_str::<Value>(line).ok()?;
+  if !object.is_object() {
+    return None;
+  }
+  let field = |path: &str| json_field(&object, path).map(json_string);
+  Some(Line {
+    prefix: field(&fields.prefix).filter(|p| !p.is_empty()),
+    message: field(&fields.message).unwrap_or_else(|| line.to_string()),
+    level: field(&fields.level),
+    timestamp: field(&fields.timestamp),
+    raw: Some(line.to_string()),
+    ..Default::default()
+  })
+}
+
+/// Splits a logfmt line like `level=info msg="hello world" cached` into key value pairs. Keys
+/// without a value are `true`. Returns `None` if there isn't a single `key=value` pair
+fn logfmt_pairs(line: &str) -> Option<Vec<(String, String)>> {
+  let mut pairs = vec![];
+  let mut has_value = false;
+  let mut chars = line.chars().peekable();
+  loop {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    if chars.peek().is_none() {
+      break;
+    }
+    let mut key = String::new();
+    while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+      key.push(c);
+    }
+    if chars.next_if_eq(&'=').is_none() {
+      pairs.push((key, "true".to_string()));
+      continue;
+    }
+    has_value = true;
+    let mut value = String::new();
+    if chars.next_if_eq(&'"').is_some() {
+      while let Some(c) = chars.next() {
+        match c {
+          '"' => break,
+          '\\' => value.extend(chars.next()),
+          c => value.push(c),
+        }
+      }
+    } else {
+      while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+        value.push(c);
+      }
+    }
+    pairs.push((key, value));
+  }
+  has_value.then_some(pairs)
+}
+
+/// Parses a logfmt line. Returns `None` if the line has no `key=value` pairs. The line is kept as
+/// `raw` so all the pairs can be shown
+pub fn parse_logfmt(line: &str, fields: &FieldNames) -> Option<Line> {
+  let pairs = logfmt_pairs(line)?;
+  let field = |key: &str| {
+    pairs
+      .iter()
+      .find(|(k, _)| k == key)
+      .map(|(_, value)| value.clone())
+  };
+  Some(Line {
+    prefix: field(&fields.prefix).filter(|p| !p.is_empty()),
+    message: field(&fields.message).unwrap_or_else(|| line.to_string()),
+    level: field(&fields.level),
+    timestamp: field(&fields.timestamp),
+    raw: Some(line.to_string()),
+    ..Default::default()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fields(prefix: &str) -> FieldNames {
+    FieldNames {
+      prefix: prefix.to_string(),
+      message: "msg".to_string(),
+      level: "level".to_string(),
+      timestamp: "time".to_string(),
+    }
+  }
+
+  #[test]
+  fn json_fields_follow_dotted_paths() {
+    let line = r#"{"kubernetes":{"pod":"api-1"},"msg":"ready","level":30,"time":"12:00"}"#;
+    let parsed = parse_json(line, &fields("kubernetes.pod")).unwrap();
+    assert_eq!(parsed.prefix.as_deref(), Some("api-1"));
+    assert_eq!(parsed.message, "ready");
+    assert_eq!(parsed.level.as_deref(), Some("30"));
+    assert_eq!(parsed.timestamp.as_deref(), Some("12:00"));
+    assert_eq!(parsed.raw.as_deref(), Some(line));
+  }
+
+  #[test]
+  fn json_paths_through_missing_or_scalar_fields_are_none() {
+    let line = r#"{"kubernetes":"api","msg":"ready"}"#;
+    assert_eq!(
+      parse_json(line, &fields("kubernetes.pod")).unwrap().prefix,
+      None
+    );
+    assert_eq!(
+      parse_json(line, &fields("service.name")).unwrap().prefix,
+      None
+    );
+  }
+
+  #[test]
+  fn json_without_a_message_keeps_the_line() {
+    let line = r#"{"service":"api"}"#;
+    let parsed = parse_json(line, &fields("service")).unwrap();
+    assert_eq!(parsed.prefix.as_deref(), Some("api"));
+    assert_eq!(parsed.message, line);
+  }
+
+  #[test]
+  fn json_lines_must_be_objects() {
+    assert!(parse_json("[1, 2]", &fields("service")).is_none());
+    assert!(parse_json("\"text\"", &fields("service")).is_none());
+    assert!(parse_json("service: text", &fields("service")).is_none());
+  }
+
+  fn pairs(line: &str) -> Vec<(String, String)> {
+    logfmt_pairs(line).unwrap()
+  }
+
+  fn pair(key: &str, value: &str) -> (String, String) {
+    (key.to_string(), value.to_string())
+  }
+
+  #[test]
+  fn logfmt_values_can_be_quoted() {
+    assert_eq!(
+      pairs(r#"level=info msg="hello world" empty="" port=80"#),
+      vec![
+        pair("level", "info"),
+        pair("msg", "hello world"),
+        pair("empty", ""),
+        pair("port", "80"),
+      ]
+    );
+  }
+
+  #[test]
+  fn logfmt_quoted_values_unescape_quotes_and_backslashes() {
+    assert_eq!(
+      pairs(r#"msg="say \"hi\" to C:\\temp" ok=1"#),
+      vec![pair("msg", r#"say "hi" to C:\temp"#), pair("ok", "1")]
+    );
+  }
+
+  #[test]
+  fn logfmt_keys_without_values_are_true() {
+    assert_eq!(
+      pairs("cached  level=debug"),
+      vec![pair("cached", "true"), pair("level", "debug")]
+    );
+  }
+
+  #[test]
+  fn logfmt_needs_a_key_value_pair() {
+    assert_eq!(logfmt_pairs("just some words"), None);
+    assert_eq!(logfmt_pairs(""), None);
+  }
+
+  #[test]
+  fn logfmt_fields_split_the_line() {
+    let line = r#"time=12:00 service=api level=warn msg="slow query""#;
+    let parsed = parse_logfmt(line, &fields("service")).unwrap();
+    assert_eq!(parsed.prefix.as_deref(), Some("api"));
+    assert_eq!(parsed.message, "slow query");
+    assert_eq!(parsed.level.as_deref(), Some("warn"));
+    assert_eq!(parsed.timestamp.as_deref(), Some("12:00"));
+  }
+}
@@ -0,0 +1,90 @@
+use crate::app::{lines_fitting, Line};
+use crate::bucket::Bucket;
+
+/// A view of several buckets merged together in the order their lines arrived.
+///
+/// The scroll position is the sequence number of the first shown line rather than an index, so it
+/// stays on the same lines when the buckets drop old lines.
+#[derive(Clone, Debug, Default)]
+pub struct Timeline {
+  pub scroll: Option<u64>,
+}
+
+impl Timeline {
+  pub fn get_lines<'a>(&self, buckets: &[&'a Bucket], count: usize) -> Vec<&'a Line> {
+    match self.scroll {
+      Some(scroll) => {
+        let mut lines = buckets
+          .iter()
+          .flat_map(|b| b.get_visible_from(scroll).take(count))
+          .collect::<Vec<_>>();
+        lines.sort_by_key(|l| l.seq);
+        lines.truncate(lines_fitting(lines.iter().copied(), count));
+        lines
+      }
+      None => newest(buckets, count),
+    }
+  }
+
+  /// Number of lines before the first shown line
+  pub fn get_older(&self, buckets: &[&Bucket]) -> usize {
+    self.scroll.map_or(0, |scroll| {
+      buckets
+        .iter()
+        .map(|b| b.get_visible_before(scroll).count())
+        .sum()
+    })
+  }
+
+  pub fn scroll_up(&mut self, buckets: &[&Bucket], count: usize) {
+    self.scroll = match self.scroll {
+      Some(scroll) => buckets
+        .iter()
+        .filter_map(|b| b.get_visible_before(scroll).next())
+        .map(|l| l.seq)
+        .max()
+        .or(Some(scroll)),
+      None => {
+        let lines = newest(buckets, count + 1);
+        if lines.len() > count {
+          Some(lines[0].seq)
+        } else {
+          None
+        }
+      }
+    };
+  }
+
+  pub fn scroll_down(&mut self, buckets: &[&Bucket], count: usize) {
+    let Some(scroll) = self.scroll else {
+      return;
+    };
+    let next = buckets
+      .iter()
+      .filter_map(|b| b.get_visible_from(scroll + 1).next())
+      .map(|l| l.seq)
+      .min();
+    self.scroll = next.filter(|&next| {
+      buckets
+        .iter()
+        .map(|b| b.get_visible_from(next).take(count + 1).count())
+        .sum::<usize>()
+        > count
+    });
+  }
+
+  pub fn scroll_reset(&mut self) {
+    self.scroll = None;
+  }
+}
+
+/// The newest lines across the buckets that fit in `count` rows, oldest first
+fn newest<'a>(buckets: &[&'a Bucket], count: usize) -> Vec<&'a Line> {
+  let mut lines = buckets
+    .iter()
+    .flat_map(|b| b.get_visible_before(u64::MAX).take(count))
+    .collect::<Vec<_>>();
+  lines.sort_by_key(|l| l.seq);
+  let skip = lines.len() - lines_fitting(lines.iter().rev().copied(), count);
+  lines.split_off(skip)
+}
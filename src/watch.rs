@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::debug;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+/// Decides which changed files restart the commands
+pub struct WatchFilter {
+  include: Option<GlobSet>,
+  exclude: GlobSet,
+  /// Directories whose files never restart the commands, like `--out-dir`
+  ignored: Vec<PathBuf>,
+  cwd: PathBuf,
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet> {
+  let mut builder = GlobSetBuilder::new();
+  for glob in globs {
+    builder.add(Glob::new(glob).with_context(|| format!("Invalid glob `{}`", glob))?);
+  }
+  Ok(builder.build()?)
+}
+
+impl WatchFilter {
+  /// Creates a filter for files matching one of `include`, or any file if it is empty, and none of
+  /// `exclude`. The globs are matched against paths relative to the current directory
+  pub fn new(include: &[String], exclude: &[String], ignored: &[PathBuf]) -> Result<WatchFilter> {
+    Ok(WatchFilter {
+      include: match include {
+        [] => None,
+        include => Some(glob_set(include)?),
+      },
+      exclude: glob_set(exclude)?,
+      ignored: ignored
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .collect(),
+      cwd: env::current_dir().and_then(fs::canonicalize)?,
+    })
+  }
+
+  /// The path relative to the current directory, or as is if it is outside of it
+  fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+    path.strip_prefix(&self.cwd).unwrap_or(path)
+  }
+
+  fn matches(&self, path: &Path) -> bool {
+    if self.ignored.iter().any(|dir| path.starts_with(dir)) {
+      return false;
+    }
+    let path = self.relative(path);
+    self.include.as_ref().is_none_or(|i| i.is_match(path)) && !self.exclude.is_match(path)
+  }
+
+  /// The changed files of an event that pass the filter
+  fn changed_files(&self, event: notify::Result<Event>) -> Vec<PathBuf> {
+    match event {
+      // Files being read, e.g. by the commands themselves, don't count as changes
+      Ok(event) if matches!(event.kind, EventKind::Access(_)) => vec![],
+      Ok(event) => event
+        .paths
+        .iter()
+        .filter(|path| self.matches(path))
+        .map(|path| self.relative(path).to_path_buf())
+        .collect(),
+      Err(e) => {
+        debug!("Error watching files: {}", e);
+        vec![]
+      }
+    }
+  }
+}
+
+/// Watches the paths recursively, and sends the files that changed once none of them has changed
+/// for `debounce`
+pub fn spawn_watch_thread(
+  paths: &[PathBuf],
+  filter: WatchFilter,
+  debounce: Duration,
+) -> Result<Receiver<Vec<PathBuf>>> {
+  let (event_tx, events) = mpsc::channel::<notify::Result<Event>>();
+  let mut watcher = notify::recommended_watcher(event_tx).context("Failed to watch files")?;
+  for path in paths {
+    // Events have the path as it was watched, so it is made absolute to match against `ignored`
+    let absolute =
+      fs::canonicalize(path).with_context(|| format!("Failed to watch {}", path.display()))?;
+    watcher
+      .watch(&absolute, RecursiveMode::Recursive)
+      .with_context(|| format!("Failed to watch {}", path.display()))?;
+  }
+  let (tx, rx) = mpsc::channel::<Vec<PathBuf>>();
+  thread::spawn(move || {
+    // Files are only watched while the watcher is alive
+    let _watcher = watcher;
+    while let Ok(event) = events.recv() {
+      let mut changed = filter.changed_files(event);
+      if changed.is_empty() {
+        continue;
+      }
+      let mut quiet_at = Instant::now() + debounce;
+      loop {
+        let timeout = quiet_at.saturating_duration_since(Instant::now());
+        match events.recv_timeout(timeout) {
+          Ok(event) => {
+            let more = filter.changed_files(event);
+            if !more.is_empty() {
+              changed.extend(more);
+              quiet_at = Instant::now() + debounce;
+            }
+          }
+          Err(RecvTimeoutError::Timeout) => break,
+          Err(RecvTimeoutError::Disconnected) => return,
+        }
+      }
+      changed.sort();
+      changed.dedup();
+      if tx.send(changed).is_err() {
+        return;
+      }
+    }
+  });
+  Ok(rx)
+}